@@ -0,0 +1,124 @@
+//! Capture metadata for pairing files whose names differ.
+//!
+//! A RAW and a compressed file are considered the same shot when their EXIF
+//! DateTimeOriginal is equal and SubSecTimeOriginal, BodySerialNumber and
+//! ImageUniqueID do not contradict each other where both files have them.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use exif::{In, Reader, Tag, Value};
+
+/// How much of a TIFF-based RAW is read; the EXIF IFDs sit near the start.
+const TIFF_PREFIX: u64 = 4 * 1024 * 1024;
+
+/// The magic at the start of a Fujifilm RAF file.
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+
+/// The upper bound for the preview JPEG embedded in a RAF file.
+const RAF_MAX_PREVIEW: u32 = 64 * 1024 * 1024;
+
+/// The EXIF fields that identify a shot.
+#[derive(Clone, Debug)]
+pub struct CaptureId {
+    datetime: String,
+    subsec: Option<String>,
+    serial: Option<String>,
+    unique_id: Option<String>,
+}
+
+impl CaptureId {
+    /// Reads the capture metadata of `path`.
+    ///
+    /// Returns `None` when the file has no usable EXIF or no DateTimeOriginal,
+    /// and an error only when the file cannot be read.
+    pub fn read(path: &Path) -> io::Result<Option<CaptureId>> {
+        let mut file = File::open(path)?;
+        let mut magic = Vec::new();
+        file.by_ref().take(16).read_to_end(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut reader = Reader::new();
+        reader.continue_on_error(true);
+        let exif = if magic.starts_with(RAF_MAGIC) {
+            // RAF is not TIFF-based; its embedded preview JPEG carries the EXIF.
+            let mut header = [0u8; 92];
+            file.read_exact(&mut header)?;
+            let offset = u32::from_be_bytes(header[84..88].try_into().unwrap());
+            let length = u32::from_be_bytes(header[88..92].try_into().unwrap());
+            if length > RAF_MAX_PREVIEW {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(offset.into()))?;
+            let mut preview = Vec::with_capacity(length as usize);
+            file.take(length.into()).read_to_end(&mut preview)?;
+            reader.read_from_container(&mut Cursor::new(preview))
+        } else if magic.starts_with(b"II") || magic.starts_with(b"MM") {
+            let mut data = Vec::new();
+            file.take(TIFF_PREFIX).read_to_end(&mut data)?;
+            reader.read_raw(data)
+        } else {
+            reader.read_from_container(&mut BufReader::new(file))
+        };
+
+        let exif = match exif.or_else(|e| e.distill_partial_result(|_| {})) {
+            Ok(exif) => exif,
+            Err(exif::Error::Io(e)) => return Err(e),
+            Err(_) => return Ok(None),
+        };
+        let field = |tag| {
+            let field = exif.get_field(tag, In::PRIMARY)?;
+            let Value::Ascii(values) = &field.value else {
+                return None;
+            };
+            let text = String::from_utf8_lossy(values.first()?);
+            let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!text.is_empty()).then(|| text.to_string())
+        };
+
+        Ok(field(Tag::DateTimeOriginal).map(|datetime| CaptureId {
+            datetime,
+            subsec: field(Tag::SubSecTimeOriginal),
+            serial: field(Tag::BodySerialNumber),
+            unique_id: field(Tag::ImageUniqueID),
+        }))
+    }
+
+    /// The DateTimeOriginal, which has to be equal for a match.
+    pub fn datetime(&self) -> &str {
+        &self.datetime
+    }
+
+    /// Returns whether both ids describe the same shot.
+    pub fn matches(&self, other: &CaptureId) -> bool {
+        fn agree(a: &Option<String>, b: &Option<String>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+        self.datetime == other.datetime
+            && agree(&self.subsec, &other.subsec)
+            && agree(&self.serial, &other.serial)
+            && agree(&self.unique_id, &other.unique_id)
+    }
+
+    /// Describes the id for output, e.g. `2024:05:01 10:00:00.12, serial 1234`.
+    pub fn describe(&self) -> String {
+        let mut description = self.datetime.clone();
+        if let Some(subsec) = &self.subsec {
+            description.push('.');
+            description.push_str(subsec);
+        }
+        if let Some(serial) = &self.serial {
+            description.push_str(&format!(", serial {serial}"));
+        }
+        if let Some(unique_id) = &self.unique_id {
+            description.push_str(&format!(", id {unique_id}"));
+        }
+        description
+    }
+}
@@ -0,0 +1,296 @@
+//! Sanity checks on the RAW and compressed roots before anything is scanned.
+//!
+//! Beyond both roots being directories, these catch the mistakes that make a
+//! whole library look orphaned: overlapping roots, a RAW root without RAW
+//! files, swapped arguments, mount points with nothing mounted on them and
+//! directories standing in for a registered library. In single-root layouts
+//! both roots are the library, so only the checks on its contents apply.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use walkdir::WalkDir;
+
+use crate::{DeleteMode, formats::RawFormats, library, matching::Matcher, trash};
+
+/// How many image files of the compressed root are sampled to detect swapped roots.
+const SWAP_SAMPLE_SIZE: usize = 1000;
+
+/// Exits unless `raw` and `compressed` look like a RAW and a compressed library.
+///
+/// Problems that may be intentional only cause a warning with `force`.
+pub fn check_roots(
+    raw: &Path,
+    compressed: &Path,
+    matcher: &Matcher,
+    mode: DeleteMode,
+    force: bool,
+) {
+    let single_root = matcher.layout.is_single_root();
+    if single_root {
+        check_directory("Library", raw);
+    } else {
+        check_directory("Raw", raw);
+        check_directory("Compressed", compressed);
+    }
+    library::verify(raw);
+
+    let (Ok(raw), Ok(compressed)) = (fs::canonicalize(raw), fs::canonicalize(compressed)) else {
+        eprintln!("Error: Cannot resolve the raw and compressed directories");
+        process::exit(1);
+    };
+    if single_root {
+        check_library(&raw, matcher, mode, force);
+        return;
+    }
+    if raw == compressed {
+        eprintln!(
+            "Error: Raw and compressed directory are the same: {}",
+            raw.display()
+        );
+        process::exit(1);
+    }
+    if raw.starts_with(&compressed) || compressed.starts_with(&raw) {
+        eprintln!(
+            "Error: Raw and compressed directory are nested inside each other: {} and {}",
+            raw.display(),
+            compressed.display()
+        );
+        process::exit(1);
+    }
+
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+
+    let unmounted = unmounted_mount_points();
+    for (name, root) in [("Raw", &raw), ("Compressed", &compressed)] {
+        if let Some(mount_point) = root.ancestors().find(|a| unmounted.contains(*a)) {
+            problems.push(format!(
+                "{} directory {} is on {}, which is a mount point in /etc/fstab but has nothing mounted",
+                name,
+                root.display(),
+                mount_point.display()
+            ));
+        }
+    }
+
+    if !contains_raw(&raw, &matcher.raw_formats) {
+        let message = format!("Raw directory contains no RAW files: {}", raw.display());
+        match mode {
+            DeleteMode::Orphaned => problems.push(message),
+            DeleteMode::Matched | DeleteMode::RawOrphaned | DeleteMode::RawMatched => {
+                warnings.push(message)
+            }
+        }
+    }
+
+    let (raw_count, compressed_count) = sample_compressed(&compressed, matcher);
+    if matches!(mode, DeleteMode::RawOrphaned) && compressed_count == 0 {
+        problems.push(format!(
+            "Compressed directory contains no compressed files: {}",
+            compressed.display()
+        ));
+    }
+    if raw_count > compressed_count {
+        problems.push(format!(
+            "Compressed directory mostly contains RAW files ({} of {} sampled), \
+             were --raw and --compressed swapped? {}",
+            raw_count,
+            raw_count + compressed_count,
+            compressed.display()
+        ));
+    }
+
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    report(problems, force);
+}
+
+/// Exits on `problems`, or only warns about them with `force`.
+fn report(problems: Vec<String>, force: bool) {
+    if problems.is_empty() {
+        return;
+    }
+    if force {
+        for problem in &problems {
+            eprintln!("Warning: {} (continuing because of --force)", problem);
+        }
+        return;
+    }
+    for problem in &problems {
+        eprintln!("Error: {}", problem);
+    }
+    eprintln!("Pass --force if this is intended.");
+    process::exit(1);
+}
+
+/// Checks the root of a single-root layout, which holds both kinds of files.
+fn check_library(root: &Path, matcher: &Matcher, mode: DeleteMode, force: bool) {
+    let mut problems = Vec::new();
+    if let Some(mount_point) = unmounted_mount_points()
+        .iter()
+        .find(|mount_point| root.starts_with(mount_point))
+    {
+        problems.push(format!(
+            "Library directory {} is on {}, which is a mount point in /etc/fstab but has nothing mounted",
+            root.display(),
+            mount_point.display()
+        ));
+    }
+
+    let (raw_count, compressed_count) = sample_compressed(root, matcher);
+    if raw_count == 0 && !contains_raw(root, &matcher.raw_formats) {
+        let message = format!(
+            "Library directory contains no RAW files: {}",
+            root.display()
+        );
+        match mode {
+            DeleteMode::Orphaned => problems.push(message),
+            _ => eprintln!("Warning: {}", message),
+        }
+    }
+    if matches!(mode, DeleteMode::RawOrphaned) && compressed_count == 0 {
+        problems.push(format!(
+            "Library directory contains no compressed files: {}",
+            root.display()
+        ));
+    }
+    report(problems, force);
+}
+
+/// Exits unless `path` is an existing directory, naming it `name` in the error.
+pub fn check_directory(name: &str, path: &Path) {
+    if !path.exists() {
+        eprintln!(
+            "Error: {} directory does not exist: {}",
+            name,
+            path.display()
+        );
+        process::exit(1);
+    }
+    if !path.is_dir() {
+        eprintln!(
+            "Error: {} path is not a directory: {}",
+            name,
+            path.display()
+        );
+        process::exit(1);
+    }
+}
+
+fn contains_raw(root: &Path, raw_formats: &RawFormats) -> bool {
+    trash::skip_trash(WalkDir::new(root))
+        .filter_map(|e| e.ok())
+        .any(|entry| entry.file_type().is_file() && raw_formats.is_raw(entry.path()))
+}
+
+/// Counts RAW and compressed files among the first image files of `root`.
+fn sample_compressed(root: &Path, matcher: &Matcher) -> (usize, usize) {
+    let mut raw_count = 0usize;
+    let mut compressed_count = 0usize;
+
+    for entry in trash::skip_trash(WalkDir::new(root)).filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if matcher.raw_formats.is_raw(entry.path()) {
+            raw_count += 1;
+        } else if matcher.compressed_formats.is_compressed(entry.path()) {
+            compressed_count += 1;
+        }
+        if raw_count + compressed_count >= SWAP_SAMPLE_SIZE {
+            break;
+        }
+    }
+
+    (raw_count, compressed_count)
+}
+
+/// Returns the mount points listed in `/etc/fstab` that are not currently mounted.
+fn unmounted_mount_points() -> HashSet<PathBuf> {
+    let (Ok(fstab), Ok(mounts)) = (
+        fs::read_to_string("/etc/fstab"),
+        fs::read_to_string("/proc/self/mounts"),
+    ) else {
+        return HashSet::new();
+    };
+
+    let mounted: HashSet<PathBuf> = mount_points(&mounts).collect();
+    mount_points(&fstab)
+        .filter(|mount_point| mount_point != Path::new("/") && !mounted.contains(mount_point))
+        .collect()
+}
+
+/// Returns the second column of an fstab-formatted table.
+fn mount_points(table: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    table
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter(|field| field.starts_with('/'))
+        .map(|field| PathBuf::from(unescape_octal(field)))
+}
+
+/// Decodes the `\040`-style escapes fstab and `/proc/self/mounts` use for whitespace.
+fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(digits) = bytes.get(i + 1..i + 4)
+            && let Some(byte) = digits.iter().try_fold(0u8, |byte, digit| {
+                let digit = (b'0'..=b'7').contains(digit).then(|| digit - b'0')?;
+                byte.checked_mul(8)?.checked_add(digit)
+            })
+        {
+            decoded.push(byte);
+            i += 4;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_octal_decodes_whitespace() {
+        assert_eq!(unescape_octal(r"/mnt/My\040Photos"), "/mnt/My Photos");
+        assert_eq!(unescape_octal(r"/a\011b\134c"), "/a\tb\\c");
+        assert_eq!(unescape_octal(r"/end\040"), "/end ");
+    }
+
+    #[test]
+    fn unescape_octal_keeps_other_backslashes() {
+        assert_eq!(unescape_octal(r"/a\04"), r"/a\04");
+        assert_eq!(unescape_octal(r"/a\+12"), r"/a\+12");
+        assert_eq!(unescape_octal(r"/a\089"), r"/a\089");
+        // Above 0o377 is not a byte.
+        assert_eq!(unescape_octal(r"/a\777"), r"/a\777");
+    }
+
+    #[test]
+    fn mount_points_skip_comments_and_pseudo_filesystems() {
+        let table = "# /etc/fstab\n\
+                     UUID=1 / ext4 defaults 0 1\n\
+                     /dev/sdb1 /mnt/My\\040Photos ext4 noauto 0 2\n  \
+                     # /dev/sdc1 /mnt/old ext4 defaults 0 2\n\
+                     proc proc proc defaults 0 0\n\
+                     none swap swap sw 0 0\n";
+        let mount_points: Vec<PathBuf> = mount_points(table).collect();
+        assert_eq!(
+            mount_points,
+            [PathBuf::from("/"), PathBuf::from("/mnt/My Photos")]
+        );
+    }
+}
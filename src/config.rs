@@ -0,0 +1,109 @@
+//! The optional configuration file.
+//!
+//! Settings are read from `--config <FILE>` or, if that is not given, from
+//! `$XDG_CONFIG_HOME/photo-cleanup/config.toml` when it exists. Command-line
+//! options are applied on top of the file.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    layout::LayoutKind, mapping::RuleSpec, sidecars::ConventionSpec, stems::Normalization,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub raw: RawConfig,
+    pub compressed: CompressedConfig,
+    /// Directory mapping rules, as `[[mapping]]` tables.
+    pub mapping: Vec<RuleSpec>,
+    pub stems: StemsConfig,
+    /// Sidecar conventions added to the built-in ones, as `[[sidecars]]` tables.
+    pub sidecars: Vec<ConventionSpec>,
+    pub layout: LayoutConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RawConfig {
+    /// RAW extension specs, in the same syntax as `--raw-ext`.
+    pub extensions: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressedConfig {
+    /// Compressed format specs, in the same syntax as `--compressed-ext`.
+    pub extensions: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StemsConfig {
+    /// Stem rule presets, as for `--stem-preset`.
+    pub presets: Vec<String>,
+    /// Suffix regexes, as for `--stem-pattern`.
+    pub patterns: Vec<String>,
+    /// The Unicode form to compare stems under, as for `--normalize-stems`.
+    pub normalize: Option<Normalization>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutConfig {
+    /// How RAW and compressed files are arranged, as for `--layout`.
+    pub kind: Option<LayoutKind>,
+    /// RAW sibling folder names, as for `--raw-folders`.
+    pub raw_folders: Vec<String>,
+    /// Compressed sibling folder names, as for `--compressed-folders`.
+    pub compressed_folders: Vec<String>,
+}
+
+/// Returns `$XDG_CONFIG_HOME/photo-cleanup`.
+pub fn config_dir() -> io::Result<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "neither XDG_CONFIG_HOME nor HOME is set",
+                ));
+            }
+        },
+    };
+    Ok(config_home.join("photo-cleanup"))
+}
+
+/// Loads the configuration file, exiting when it exists but cannot be used.
+pub fn load(path: Option<&Path>) -> Config {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match config_dir() {
+            Ok(dir) if dir.join("config.toml").is_file() => dir.join("config.toml"),
+            _ => return Config::default(),
+        },
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Error: Cannot read config file {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    match toml::from_str(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: Invalid config file {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
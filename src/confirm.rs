@@ -0,0 +1,85 @@
+//! Asking the user before anything is deleted.
+
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+    process,
+};
+
+/// How many paths are shown before asking.
+const SAMPLE_SIZE: usize = 10;
+
+/// From this many files on, the user has to type the number of files instead of `y`.
+const LARGE_BATCH: usize = 100;
+
+/// Shows what is about to happen to `files` and asks for confirmation.
+///
+/// `action` describes the operation, e.g. "delete" or "move to the trash". When
+/// stdin is not a terminal, the process exits, since scripts have to pass `--yes`.
+/// With `sidecars`, the user is told that the sidecars of the files go with them.
+pub fn confirm(files: &[PathBuf], action: &str, sidecars: bool) -> bool {
+    let total_bytes: u64 = files
+        .iter()
+        .filter_map(|file| fs::symlink_metadata(file).ok())
+        .map(|meta| meta.len())
+        .sum();
+
+    println!(
+        "\nAbout to {} {} files ({}):",
+        action,
+        files.len(),
+        format_bytes(total_bytes)
+    );
+    for file in files.iter().take(SAMPLE_SIZE) {
+        println!("  {}", file.display());
+    }
+    if files.len() > SAMPLE_SIZE {
+        println!("  ... and {} more", files.len() - SAMPLE_SIZE);
+    }
+    if sidecars {
+        println!("Their sidecars go with them.");
+    }
+
+    if !io::stdin().is_terminal() {
+        eprintln!(
+            "\nError: Refusing to {} without confirmation because stdin is not a terminal. \
+             Pass --yes to skip the confirmation.",
+            action
+        );
+        process::exit(1);
+    }
+
+    if files.len() >= LARGE_BATCH {
+        print!("\nType the number of files ({}) to confirm: ", files.len());
+    } else {
+        print!("\nProceed? [y/N] ");
+    }
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim();
+    if files.len() >= LARGE_BATCH {
+        answer == files.len().to_string()
+    } else {
+        answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
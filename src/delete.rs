@@ -0,0 +1,376 @@
+//! The deletion phase shared by `clean`, `clean-matched` and `apply`.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    DeleteMode, confirm,
+    protect::Protection,
+    quarantine,
+    sidecars::{Registry, Sidecars},
+    trash,
+};
+
+/// The share of orphaned files that may be deleted when no limit is given.
+const DEFAULT_MAX_ORPHANED_PERCENT: f64 = 50.0;
+
+#[derive(Clone, Debug)]
+pub enum DeleteBackend {
+    Remove,
+    Trash,
+    Quarantine(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct DeleteOptions {
+    pub backend: DeleteBackend,
+    pub dry_run: bool,
+    /// Whether to skip the confirmation prompt.
+    pub assume_yes: bool,
+    pub verbose: bool,
+    pub summary_only: bool,
+    /// The sidecar conventions, or `None` to leave sidecars in place.
+    pub sidecars: Option<Registry>,
+    pub limits: DeleteLimits,
+}
+
+/// Limits above which a deletion looks like a misconfiguration, e.g. an empty RAW root.
+#[derive(Clone, Debug)]
+pub struct DeleteLimits {
+    /// The largest share of the scanned files, in percent, that may be deleted.
+    pub max_percent: Option<f64>,
+    /// The largest number of files that may be deleted.
+    pub max_count: Option<usize>,
+    /// Whether to go ahead even when a limit is exceeded.
+    pub force: bool,
+}
+
+impl DeleteLimits {
+    /// Describes the limit that deleting `count` of `total` files exceeds, if any.
+    fn exceeded(&self, count: usize, total: usize, mode: DeleteMode) -> Option<String> {
+        let max_percent = self.max_percent.or(match mode {
+            DeleteMode::Orphaned | DeleteMode::RawOrphaned => Some(DEFAULT_MAX_ORPHANED_PERCENT),
+            DeleteMode::Matched | DeleteMode::RawMatched => None,
+        });
+        let percent = if total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / total as f64
+        };
+        if let Some(max_percent) = max_percent
+            && percent > max_percent
+        {
+            return Some(format!(
+                "{} of {} files ({:.1}%) exceeds --max-delete-percent {}%",
+                count, total, percent, max_percent
+            ));
+        }
+        match self.max_count {
+            Some(max_count) if count > max_count => Some(format!(
+                "{} files exceeds --max-delete-count {}",
+                count, max_count
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Stops the run when deleting `count` of `total` files exceeds the limits.
+///
+/// `summary` is repeated in the refusal so the user can see what looked wrong.
+/// Dry runs and `--force` only print a warning.
+pub fn enforce_limits(
+    count: usize,
+    total: usize,
+    mode: DeleteMode,
+    summary: &[(&str, usize)],
+    options: &DeleteOptions,
+) {
+    let Some(reason) = options.limits.exceeded(count, total, mode) else {
+        return;
+    };
+
+    if options.limits.force {
+        eprintln!("\nWarning: {}; deleting anyway because of --force.", reason);
+        return;
+    }
+    if options.dry_run {
+        eprintln!(
+            "\nWarning: {}; a real run would refuse without --force.",
+            reason
+        );
+        return;
+    }
+
+    eprintln!("\nError: Refusing to delete: {}.", reason);
+    for (label, value) in summary {
+        eprintln!("  {}: {}", label, value);
+    }
+    eprintln!("  Files to delete: {}", count);
+    eprintln!(
+        "This usually means --raw or --compressed points at the wrong directory. \
+         Pass --force to delete anyway."
+    );
+    process::exit(1);
+}
+
+/// Deletes `files` below `root` with the configured backend.
+///
+/// `recheck` runs right before each file is touched, also in dry-run mode. When
+/// it returns a reason, the file is skipped and the reason is reported. Unless
+/// disabled, the sidecars of each file go with it, those that pass `recheck`
+/// as well.
+///
+/// Returns the paths that were removed, or would be in dry-run mode.
+pub fn delete_files(
+    files: &[PathBuf],
+    root: &Path,
+    options: &DeleteOptions,
+    mut recheck: impl FnMut(&Path) -> Result<(), String>,
+) -> Vec<PathBuf> {
+    let DeleteOptions {
+        backend,
+        dry_run,
+        verbose,
+        summary_only,
+        ..
+    } = options;
+    let mut skipped = Vec::new();
+    let mut removed = Vec::new();
+    let mut sidecars = options.sidecars.as_ref().map(Sidecars::new);
+    let mut protection = Protection::new(root);
+    let mut sidecars_of = |file: &Path| -> Vec<PathBuf> {
+        let Some(sidecars) = sidecars.as_mut() else {
+            return Vec::new();
+        };
+        let mut group = sidecars.take(file).unwrap_or_else(|e| {
+            eprintln!(
+                "  Warning: Cannot look for sidecars of {}: {}",
+                file.display(),
+                e
+            );
+            Vec::new()
+        });
+        // A sidecar can be protected by a pattern even when its image is not.
+        group.retain(|sidecar| match protection.reason(sidecar) {
+            Some(reason) => {
+                if !summary_only {
+                    println!(
+                        "  Keeping protected sidecar {} ({})",
+                        sidecar.display(),
+                        reason
+                    );
+                }
+                false
+            }
+            None => true,
+        });
+        group
+    };
+
+    if *dry_run {
+        if !summary_only {
+            println!("\nDry run mode - files that would be deleted:");
+        }
+        let mut would_delete = 0usize;
+        let mut would_delete_sidecars = 0usize;
+        for file in files {
+            if let Err(reason) = recheck(file) {
+                if !summary_only {
+                    println!("  SKIP {} ({})", file.display(), reason);
+                }
+                skipped.push(file.clone());
+                continue;
+            }
+            would_delete += 1;
+            let mut group = sidecars_of(file);
+            recheck_sidecars(&mut group, &mut recheck, *summary_only);
+            would_delete_sidecars += group.len();
+            removed.push(file.clone());
+            removed.extend(group.iter().cloned());
+            if *summary_only {
+                continue;
+            }
+            for (i, path) in [file].into_iter().chain(&group).enumerate() {
+                let indent = if i == 0 { "  " } else { "    + " };
+                match backend {
+                    DeleteBackend::Remove => println!("{}{}", indent, path.display()),
+                    DeleteBackend::Trash => match trash::trash_dir_for(path) {
+                        Ok(trash_dir) => {
+                            println!("{}{} -> {}", indent, path.display(), trash_dir.display())
+                        }
+                        Err(e) => {
+                            println!("{}{} -> no usable trash: {}", indent, path.display(), e)
+                        }
+                    },
+                    DeleteBackend::Quarantine(quarantine_dir) => println!(
+                        "{}{} -> {}",
+                        indent,
+                        path.display(),
+                        quarantine::preview_destination(quarantine_dir, root, path).display()
+                    ),
+                }
+            }
+        }
+        if *summary_only {
+            println!("\nDry run mode - {} files would be deleted.", would_delete);
+        }
+        if would_delete_sidecars > 0 {
+            println!("{} sidecars would go with them", would_delete_sidecars);
+        }
+        if !skipped.is_empty() {
+            println!("{} files would be skipped", skipped.len());
+        }
+        return removed;
+    }
+
+    if !options.assume_yes {
+        let action = match backend {
+            DeleteBackend::Remove => "delete",
+            DeleteBackend::Trash => "move to the trash",
+            DeleteBackend::Quarantine(_) => "quarantine",
+        };
+        if !confirm::confirm(files, action, options.sidecars.is_some()) {
+            println!("\nAborted, no files were touched.");
+            return removed;
+        }
+    }
+
+    let mut run = None;
+    match backend {
+        DeleteBackend::Remove => println!("\nDeleting {} files...", files.len()),
+        DeleteBackend::Trash => println!("\nMoving {} files to the trash...", files.len()),
+        DeleteBackend::Quarantine(quarantine_dir) => {
+            match quarantine::Run::create(quarantine_dir, root) {
+                Ok(created) => {
+                    println!(
+                        "\nMoving {} files to quarantine run {}...",
+                        files.len(),
+                        created.id()
+                    );
+                    run = Some(created);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "\nError creating quarantine run in {}: {}",
+                        quarantine_dir.display(),
+                        e
+                    );
+                    return removed;
+                }
+            }
+        }
+    }
+
+    let mut remove = |path: &Path| -> io::Result<Option<PathBuf>> {
+        match (backend, run.as_mut()) {
+            (DeleteBackend::Trash, _) => trash::trash_file(path).map(Some),
+            (DeleteBackend::Quarantine(_), Some(run)) => run.quarantine(path).map(Some),
+            _ => fs::remove_file(path).map(|_| None),
+        }
+    };
+    let mut errors = Vec::new();
+    let mut deleted_sidecars = 0usize;
+    for file in files {
+        if let Err(reason) = recheck(file) {
+            if !summary_only {
+                println!("  Skipped: {} ({})", file.display(), reason);
+            }
+            skipped.push(file.clone());
+            continue;
+        }
+
+        match remove(file) {
+            Ok(destination) => {
+                removed.push(file.clone());
+                if *verbose && !summary_only {
+                    print_removed(file, destination.as_deref(), "");
+                }
+            }
+            Err(e) => {
+                eprintln!("  Error deleting {}: {}", file.display(), e);
+                errors.push(file.clone());
+                continue;
+            }
+        }
+        let mut group = sidecars_of(file);
+        recheck_sidecars(&mut group, &mut recheck, *summary_only);
+        for sidecar in group {
+            match remove(&sidecar) {
+                Ok(destination) => {
+                    removed.push(sidecar.clone());
+                    deleted_sidecars += 1;
+                    if *verbose && !summary_only {
+                        print_removed(&sidecar, destination.as_deref(), " sidecar");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("  Error deleting sidecar {}: {}", sidecar.display(), e);
+                    errors.push(sidecar);
+                }
+            }
+        }
+    }
+
+    if !skipped.is_empty() {
+        println!(
+            "\nSkipped {} files that no longer passed the pre-deletion checks",
+            skipped.len()
+        );
+    }
+    if !errors.is_empty() {
+        eprintln!("\nEncountered {} errors during deletion", errors.len());
+    } else {
+        let count = files.len() - skipped.len();
+        match (backend, &run) {
+            (DeleteBackend::Trash, _) => println!("\nMoved {} files to the trash", count),
+            (DeleteBackend::Quarantine(_), Some(run)) => {
+                println!("\nQuarantined {} files in run {}", count, run.id())
+            }
+            _ => println!("\nSuccessfully deleted all {} files", count),
+        }
+    }
+    if deleted_sidecars > 0 {
+        println!("{} sidecars went with them", deleted_sidecars);
+    }
+    if let (Some(run), DeleteBackend::Quarantine(quarantine_dir)) = (run, backend) {
+        println!(
+            "Restore them with: photo-cleanup restore --quarantine {} {}",
+            quarantine_dir.display(),
+            run.id()
+        );
+    }
+    removed
+}
+
+/// Keeps the sidecars in `group` that do not pass `recheck` in place.
+fn recheck_sidecars(
+    group: &mut Vec<PathBuf>,
+    recheck: &mut impl FnMut(&Path) -> Result<(), String>,
+    summary_only: bool,
+) {
+    group.retain(|sidecar| match recheck(sidecar) {
+        Ok(()) => true,
+        Err(reason) => {
+            if !summary_only {
+                println!("  Keeping sidecar {} ({})", sidecar.display(), reason);
+            }
+            false
+        }
+    });
+}
+
+fn print_removed(path: &Path, destination: Option<&Path>, kind: &str) {
+    match destination {
+        Some(destination) => println!(
+            "  Moved{}: {} -> {}",
+            kind,
+            path.display(),
+            destination.display()
+        ),
+        None => println!("  Deleted{}: {}", kind, path.display()),
+    }
+}
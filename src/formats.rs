@@ -0,0 +1,270 @@
+//! The registries of camera RAW formats and of the compressed formats derived from them.
+
+use std::{collections::BTreeSet, ffi::OsStr, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// Camera RAW extensions grouped by vendor, in lowercase.
+pub const RAW_VENDORS: &[(&str, &[&str])] = &[
+    ("adobe", &["dng"]),
+    ("canon", &["crw", "cr2", "cr3"]),
+    ("epson", &["erf"]),
+    ("fujifilm", &["raf"]),
+    ("gopro", &["gpr"]),
+    ("hasselblad", &["3fr", "fff"]),
+    ("kodak", &["dcr", "dcs", "kdc"]),
+    ("leaf", &["mos"]),
+    ("leica", &["rwl", "dng"]),
+    ("mamiya", &["mef"]),
+    ("minolta", &["mrw"]),
+    ("nikon", &["nef", "nrw"]),
+    ("olympus", &["orf", "ori"]),
+    ("panasonic", &["rw2", "raw"]),
+    ("pentax", &["pef", "dng"]),
+    ("phaseone", &["iiq"]),
+    ("samsung", &["srw"]),
+    ("sigma", &["x3f"]),
+    ("sony", &["arw", "srf", "sr2"]),
+];
+
+/// Compressed image formats by name, with their extensions in lowercase.
+pub const COMPRESSED_FORMATS: &[(&str, &[&str])] = &[
+    ("jpeg", &["jpg", "jpeg"]),
+    ("heif", &["heic", "heif", "hif"]),
+    ("avif", &["avif"]),
+    ("jxl", &["jxl"]),
+    ("png", &["png"]),
+    ("tiff", &["tif", "tiff"]),
+    ("webp", &["webp"]),
+];
+
+/// The compressed formats selected when none are configured. HEIF has to be
+/// opted into like exports, since phones put HEICs without a RAW into the
+/// same libraries. The rare `.jpe` is left out of `jpeg` and can be added as
+/// an extension.
+const DEFAULT_COMPRESSED_FORMATS: &[&str] = &["jpeg"];
+
+/// The set of extensions that count as RAW files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawFormats {
+    extensions: BTreeSet<String>,
+}
+
+impl RawFormats {
+    /// Returns every extension of the registry.
+    pub fn builtin() -> RawFormats {
+        RawFormats {
+            extensions: RAW_VENDORS
+                .iter()
+                .flat_map(|(_, extensions)| extensions.iter())
+                .map(|ext| ext.to_string())
+                .collect(),
+        }
+    }
+
+    /// Adjusts the set with specs like `fujifilm`, `+pef` or `-raw`.
+    ///
+    /// A spec is a vendor name from the registry or an extension. `+` adds and
+    /// `-` removes it. When there are specs without a sign, the set is first
+    /// restricted to just those.
+    pub fn apply(&mut self, specs: &[String]) -> Result<(), String> {
+        apply_specs(&mut self.extensions, RAW_VENDORS, "RAW vendor", specs)
+    }
+
+    /// Returns whether `ext` is a RAW extension, in any mix of upper and lower case.
+    pub fn contains(&self, ext: &OsStr) -> bool {
+        ext.to_str()
+            .is_some_and(|ext| self.extensions.contains(&ext.to_ascii_lowercase()))
+    }
+
+    pub fn is_raw(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|ext| self.contains(ext))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+}
+
+impl Default for RawFormats {
+    fn default() -> RawFormats {
+        RawFormats::builtin()
+    }
+}
+
+/// The set of extensions that count as compressed files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CompressedFormats {
+    extensions: BTreeSet<String>,
+}
+
+impl CompressedFormats {
+    /// Returns the extensions of the default formats.
+    pub fn builtin() -> CompressedFormats {
+        let mut extensions = BTreeSet::new();
+        for format in DEFAULT_COMPRESSED_FORMATS {
+            extensions.extend(resolve(COMPRESSED_FORMATS, "format", format).unwrap());
+        }
+        CompressedFormats { extensions }
+    }
+
+    /// Adjusts the set with specs like `heif`, `+tiff` or `+jpe`, see [`RawFormats::apply`].
+    pub fn apply(&mut self, specs: &[String]) -> Result<(), String> {
+        apply_specs(&mut self.extensions, COMPRESSED_FORMATS, "format", specs)
+    }
+
+    /// Returns the name of the format of `path` if it is a selected compressed file.
+    ///
+    /// Extensions that are not in the registry are their own format.
+    pub fn format_of(&self, path: &Path) -> Option<String> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        if !self.extensions.contains(&ext) {
+            return None;
+        }
+        let format = COMPRESSED_FORMATS
+            .iter()
+            .find(|(_, extensions)| extensions.contains(&ext.as_str()))
+            .map_or(ext.clone(), |(format, _)| format.to_string());
+        Some(format)
+    }
+
+    pub fn is_compressed(&self, path: &Path) -> bool {
+        self.format_of(path).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+}
+
+impl Default for CompressedFormats {
+    fn default() -> CompressedFormats {
+        CompressedFormats::builtin()
+    }
+}
+
+/// Applies `+`/`-`/plain specs to `extensions`, resolving names via `registry`.
+fn apply_specs(
+    extensions: &mut BTreeSet<String>,
+    registry: &[(&str, &[&str])],
+    kind: &str,
+    specs: &[String],
+) -> Result<(), String> {
+    let mut only = BTreeSet::new();
+    let mut add = BTreeSet::new();
+    let mut remove = BTreeSet::new();
+
+    for spec in specs {
+        let spec = spec.trim();
+        let (target, name) = match spec.as_bytes().first() {
+            Some(b'+') => (&mut add, &spec[1..]),
+            Some(b'-') => (&mut remove, &spec[1..]),
+            _ => (&mut only, spec),
+        };
+        target.extend(resolve(registry, kind, name)?);
+    }
+
+    if !only.is_empty() {
+        *extensions = only;
+    }
+    extensions.extend(add);
+    extensions.retain(|ext| !remove.contains(ext));
+    Ok(())
+}
+
+/// Returns the extensions a registry name or extension stands for.
+fn resolve(registry: &[(&str, &[&str])], kind: &str, name: &str) -> Result<Vec<String>, String> {
+    let name = name.trim_start_matches('.').to_ascii_lowercase();
+    if let Some((_, extensions)) = registry.iter().find(|(group, _)| *group == name) {
+        return Ok(extensions.iter().map(|ext| ext.to_string()).collect());
+    }
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(vec![name])
+    } else {
+        Err(format!("`{name}` is neither a {kind} nor an extension"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(extensions: &[&str], specs: &[&str]) -> Result<Vec<String>, String> {
+        let mut extensions = extensions.iter().map(|ext| ext.to_string()).collect();
+        let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+        apply_specs(&mut extensions, RAW_VENDORS, "RAW vendor", &specs)?;
+        Ok(extensions.into_iter().collect())
+    }
+
+    #[test]
+    fn plain_specs_restrict_the_set() {
+        assert_eq!(apply(&["nef", "raf"], &["fujifilm"]).unwrap(), ["raf"]);
+        assert_eq!(
+            apply(&["nef"], &["canon", "pef"]).unwrap(),
+            ["cr2", "cr3", "crw", "pef"]
+        );
+    }
+
+    #[test]
+    fn signed_specs_adjust_the_set() {
+        assert_eq!(apply(&["nef"], &["+pef", "-nef"]).unwrap(), ["pef"]);
+        assert_eq!(apply(&["raw", "rw2"], &["-raw"]).unwrap(), ["rw2"]);
+        // Removals win over the restriction and additions.
+        assert_eq!(
+            apply(&["nef"], &["fujifilm", "+.DNG", "-dng"]).unwrap(),
+            ["raf"]
+        );
+    }
+
+    #[test]
+    fn specs_ignore_case_dots_and_blanks() {
+        assert_eq!(
+            apply(&[], &[" .RAF", "+Nikon "]).unwrap(),
+            ["nef", "nrw", "raf"]
+        );
+    }
+
+    #[test]
+    fn invalid_specs_leave_the_set_unchanged() {
+        let mut extensions = BTreeSet::from(["nef".to_string()]);
+        for spec in ["", "+", "-r.w", "foo/bar"] {
+            assert!(
+                apply_specs(
+                    &mut extensions,
+                    RAW_VENDORS,
+                    "RAW vendor",
+                    &[spec.to_string()]
+                )
+                .is_err()
+            );
+        }
+        assert_eq!(extensions, BTreeSet::from(["nef".to_string()]));
+    }
+
+    #[test]
+    fn extensions_match_in_any_case() {
+        let raw = RawFormats::builtin();
+        assert!(raw.is_raw(Path::new("a/DSCF1234.Raf")));
+        assert!(!raw.is_raw(Path::new("a/DSCF1234.jpg")));
+        let compressed = CompressedFormats::builtin();
+        assert_eq!(
+            compressed.format_of(Path::new("a.JPEG")).as_deref(),
+            Some("jpeg")
+        );
+        assert_eq!(compressed.format_of(Path::new("a.heic")), None);
+    }
+
+    #[test]
+    fn jpe_is_opt_in() {
+        let mut compressed = CompressedFormats::builtin();
+        assert_eq!(compressed.format_of(Path::new("a.jpe")), None);
+        compressed.apply(&["+jpe".to_string()]).unwrap();
+        assert_eq!(
+            compressed.format_of(Path::new("a.JPE")).as_deref(),
+            Some("jpe")
+        );
+        assert!(compressed.is_compressed(Path::new("a.jpg")));
+    }
+}
@@ -0,0 +1,122 @@
+//! Small filesystem helpers shared by the commands that move or delete files.
+
+use std::{
+    fs::{self, File, FileTimes},
+    io,
+    path::Path,
+};
+
+/// Moves `from` to `to`, creating the parent directories of `to`.
+///
+/// Falls back to copy and delete when the two paths are on different volumes,
+/// keeping the modification time. Refuses to overwrite an existing file at `to`.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if let Err(e) = copy_with_times(from, to) {
+                // A partial copy must not be mistaken for the file.
+                let _ = fs::remove_file(to);
+                return Err(e);
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Copies `from` to `to` with its access and modification times.
+fn copy_with_times(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::metadata(from)?;
+    fs::copy(from, to)?;
+    let times = FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(meta.modified()?);
+    File::options().write(true).open(to)?.set_times(times)
+}
+
+/// Returns whether `path` is `root` or lies below it, without requiring `path` to exist.
+pub fn is_within(path: &Path, root: &Path) -> bool {
+    let (Ok(path), Ok(root)) = (std::path::absolute(path), fs::canonicalize(root)) else {
+        return false;
+    };
+    let path = path
+        .ancestors()
+        .find_map(|ancestor| {
+            let canonical = fs::canonicalize(ancestor).ok()?;
+            Some(canonical.join(path.strip_prefix(ancestor).ok()?))
+        })
+        .unwrap_or(path);
+    path.starts_with(root)
+}
+
+/// Serializes a path losslessly for `#[serde(with = "fsutil::lossless")]`: as
+/// a string when it is UTF-8, and as the array of its bytes otherwise.
+pub mod lossless {
+    use std::{
+        ffi::OsString,
+        os::unix::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+    };
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Encoded {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    impl From<Encoded> for PathBuf {
+        fn from(encoded: Encoded) -> PathBuf {
+            match encoded {
+                Encoded::Text(text) => PathBuf::from(text),
+                Encoded::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => serializer.serialize_str(text),
+            None => serializer.collect_seq(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Encoded::deserialize(deserializer).map(PathBuf::from)
+    }
+
+    /// The same for an optional path.
+    pub mod option {
+        use std::path::PathBuf;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Ok(Option::<super::Encoded>::deserialize(deserializer)?.map(PathBuf::from))
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! How RAW and compressed files are arranged relative to each other.
+//!
+//! - `parallel`: two roots with the same directory structure, e.g.
+//!   `raw/2024/DSCF0001.RAF` and `jpeg/2024/DSCF0001.JPG`.
+//! - `mixed`: one root with RAW and compressed files side by side, as cameras
+//!   write them, e.g. `DCIM/100_FUJI/DSCF0001.RAF` and `DSCF0001.JPG`.
+//! - `sibling`: one root with a RAW and a compressed folder in every shoot,
+//!   e.g. `2024/Trip/RAW/DSCF0001.RAF` and `2024/Trip/JPG/DSCF0001.JPG`.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// The RAW folder names of the sibling layout, in the order they are tried.
+pub const DEFAULT_RAW_FOLDERS: &[&str] = &["RAW", "Raw", "raw"];
+
+/// The compressed folder names of the sibling layout.
+pub const DEFAULT_COMPRESSED_FOLDERS: &[&str] = &["JPG", "Jpg", "jpg", "JPEG", "Jpeg", "jpeg"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutKind {
+    /// Separate RAW and compressed roots with the same structure.
+    #[default]
+    Parallel,
+    /// RAW and compressed files in the same directories of one root.
+    Mixed,
+    /// RAW and compressed sibling folders in every shoot folder of one root.
+    Sibling,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Layout {
+    pub kind: LayoutKind,
+    /// The names of RAW sibling folders, in the order they are tried.
+    pub raw_folders: Vec<String>,
+    /// The names of compressed sibling folders.
+    pub compressed_folders: Vec<String>,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout {
+            kind: LayoutKind::Parallel,
+            raw_folders: DEFAULT_RAW_FOLDERS.iter().map(|s| s.to_string()).collect(),
+            compressed_folders: DEFAULT_COMPRESSED_FOLDERS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl LayoutKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            LayoutKind::Parallel => "parallel",
+            LayoutKind::Mixed => "mixed",
+            LayoutKind::Sibling => "sibling",
+        }
+    }
+}
+
+impl Layout {
+    /// Creates a layout, with the default sibling folder names for empty lists.
+    pub fn new(
+        kind: LayoutKind,
+        raw_folders: &[String],
+        compressed_folders: &[String],
+    ) -> Result<Layout, String> {
+        let defaults = Layout::default();
+        let layout = Layout {
+            kind,
+            raw_folders: if raw_folders.is_empty() {
+                defaults.raw_folders
+            } else {
+                raw_folders.to_vec()
+            },
+            compressed_folders: if compressed_folders.is_empty() {
+                defaults.compressed_folders
+            } else {
+                compressed_folders.to_vec()
+            },
+        };
+        for name in layout.raw_folders.iter().chain(&layout.compressed_folders) {
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                return Err(format!("Invalid sibling folder name `{}`", name));
+            }
+        }
+        if let Some(name) = layout
+            .raw_folders
+            .iter()
+            .find(|name| layout.compressed_folders.contains(name))
+        {
+            return Err(format!(
+                "`{}` cannot be both a RAW and a compressed folder",
+                name
+            ));
+        }
+        Ok(layout)
+    }
+
+    /// Returns whether the layout keeps both kinds of files below one root.
+    pub fn is_single_root(&self) -> bool {
+        self.kind != LayoutKind::Parallel
+    }
+
+    /// Returns whether the compressed file at `relative_path` is part of the
+    /// layout; in the sibling layout only files in compressed folders are.
+    pub fn includes_compressed(&self, relative_path: &Path) -> bool {
+        self.kind != LayoutKind::Sibling
+            || sibling_folder(relative_path.parent(), &self.compressed_folders).is_some()
+    }
+
+    /// Returns whether the RAW file at `relative_path` is part of the layout.
+    pub fn includes_raw(&self, relative_path: &Path) -> bool {
+        self.kind != LayoutKind::Sibling
+            || sibling_folder(relative_path.parent(), &self.raw_folders).is_some()
+    }
+
+    /// Returns the RAW directory a compressed file in `dir` pairs with: `dir`
+    /// itself, or in the sibling layout the same place in the first RAW folder
+    /// of the shoot for which `exists` holds, or else in its first existing
+    /// RAW folder.
+    ///
+    /// Fails for a directory outside the compressed folders of the sibling
+    /// layout, and for a shoot without any of the RAW folders, whose RAWs may
+    /// be in a folder of another name.
+    pub fn raw_dir(&self, dir: &Path, exists: impl Fn(&Path) -> bool) -> Result<PathBuf, String> {
+        if self.kind != LayoutKind::Sibling {
+            return Ok(dir.to_path_buf());
+        }
+        let Some((shoot, rest)) = sibling_folder(Some(dir), &self.compressed_folders) else {
+            return Err("not in a compressed folder of the sibling layout".to_string());
+        };
+        let folders: Vec<PathBuf> = self
+            .raw_folders
+            .iter()
+            .map(|folder| shoot.join(folder))
+            .filter(|folder| exists(folder))
+            .collect();
+        let candidates: Vec<PathBuf> = folders.iter().map(|folder| folder.join(&rest)).collect();
+        candidates
+            .iter()
+            .find(|candidate| exists(candidate))
+            .or(candidates.first())
+            .cloned()
+            .ok_or_else(|| {
+                let shoot = if shoot.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    &shoot
+                };
+                format!(
+                    "shoot {} has none of the RAW folders {}",
+                    shoot.display(),
+                    self.raw_folders.join(", ")
+                )
+            })
+    }
+
+    /// Returns the directory a RAW or compressed file in `dir` would have
+    /// without the layout's folders, e.g. `Trip/sub` for `Trip/RAW/sub` in
+    /// the sibling layout, or `None` outside the sibling folders.
+    pub fn strip_folder(&self, dir: &Path, raw: bool) -> Option<PathBuf> {
+        if self.kind != LayoutKind::Sibling {
+            return Some(dir.to_path_buf());
+        }
+        let names = if raw {
+            &self.raw_folders
+        } else {
+            &self.compressed_folders
+        };
+        sibling_folder(Some(dir), names).map(|(shoot, rest)| shoot.join(rest))
+    }
+
+    /// Returns the directory of a RAW or compressed file that belongs in
+    /// `dir`, which is a shoot folder in the sibling layout, so the file goes
+    /// into its first RAW or compressed folder.
+    pub fn add_folder(&self, dir: &Path, raw: bool) -> PathBuf {
+        let names = if raw {
+            &self.raw_folders
+        } else {
+            &self.compressed_folders
+        };
+        match names.first() {
+            Some(name) if self.kind == LayoutKind::Sibling => dir.join(name),
+            _ => dir.to_path_buf(),
+        }
+    }
+
+    /// Returns the shoot folder of a file in the sibling layout.
+    pub fn shoot(&self, relative_path: &Path) -> Option<PathBuf> {
+        if self.kind != LayoutKind::Sibling {
+            return None;
+        }
+        let parent = relative_path.parent();
+        sibling_folder(parent, &self.compressed_folders)
+            .or_else(|| sibling_folder(parent, &self.raw_folders))
+            .map(|(shoot, _)| shoot)
+    }
+}
+
+/// Splits `dir` at its innermost component named one of `names` into the
+/// shoot folder before it and the path below it.
+fn sibling_folder(dir: Option<&Path>, names: &[String]) -> Option<(PathBuf, PathBuf)> {
+    let components: Vec<&OsStr> = dir?.iter().collect();
+    let i = components
+        .iter()
+        .rposition(|component| names.iter().any(|name| OsStr::new(name) == *component))?;
+    Some((
+        components[..i].iter().collect(),
+        components[i + 1..].iter().collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sibling() -> Layout {
+        Layout::new(LayoutKind::Sibling, &[], &[]).unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn sibling_folder_splits_at_the_innermost_folder() {
+        let names = names(&["RAW", "JPG"]);
+        let split = |dir: &str| sibling_folder(Some(Path::new(dir)), &names);
+        assert_eq!(
+            split("2024/Trip/JPG/sub"),
+            Some((PathBuf::from("2024/Trip"), PathBuf::from("sub")))
+        );
+        assert_eq!(
+            split("2024/JPG/Trip/RAW"),
+            Some((PathBuf::from("2024/JPG/Trip"), PathBuf::new()))
+        );
+        assert_eq!(split("JPG"), Some((PathBuf::new(), PathBuf::new())));
+        assert_eq!(split("2024/Trip/jpg"), None);
+        assert_eq!(split("2024/Trip/JPGs"), None);
+        assert_eq!(sibling_folder(None, &names), None);
+    }
+
+    #[test]
+    fn raw_dir_prefers_an_existing_folder() {
+        let layout = sibling();
+        let existing = |paths: &'static [&'static str]| {
+            move |path: &Path| paths.iter().any(|existing| Path::new(existing) == path)
+        };
+        assert_eq!(
+            layout
+                .raw_dir(
+                    Path::new("Trip/JPG/sub"),
+                    existing(&["Trip/RAW", "Trip/raw", "Trip/raw/sub"])
+                )
+                .unwrap(),
+            Path::new("Trip/raw/sub")
+        );
+        assert_eq!(
+            layout
+                .raw_dir(
+                    Path::new("Trip/JPG/sub"),
+                    existing(&["Trip/Raw", "Trip/raw"])
+                )
+                .unwrap(),
+            Path::new("Trip/Raw/sub")
+        );
+        assert!(
+            layout
+                .raw_dir(Path::new("Trip/JPG"), existing(&["Trip/NEF"]))
+                .is_err()
+        );
+        assert!(
+            layout
+                .raw_dir(Path::new("Trip/Export"), existing(&["Trip/RAW"]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn other_layouts_keep_the_directory() {
+        let layout = Layout::default();
+        assert_eq!(
+            layout.raw_dir(Path::new("2024/JPG"), |_| false).unwrap(),
+            Path::new("2024/JPG")
+        );
+        assert_eq!(layout.shoot(Path::new("2024/JPG/a.jpg")), None);
+        assert!(layout.includes_compressed(Path::new("a.jpg")));
+    }
+
+    #[test]
+    fn folders_are_stripped_and_added() {
+        let layout = sibling();
+        assert_eq!(
+            layout.strip_folder(Path::new("Trip/RAW/sub"), true),
+            Some(PathBuf::from("Trip/sub"))
+        );
+        assert_eq!(layout.strip_folder(Path::new("Trip/RAW/sub"), false), None);
+        assert_eq!(
+            layout.add_folder(Path::new("Trip"), false),
+            Path::new("Trip/JPG")
+        );
+        assert_eq!(
+            layout.shoot(Path::new("Trip/Raw/a.raf")),
+            Some(PathBuf::from("Trip"))
+        );
+        assert!(layout.includes_raw(Path::new("Trip/raw/a.raf")));
+        assert!(!layout.includes_raw(Path::new("Trip/a.raf")));
+    }
+
+    #[test]
+    fn invalid_folder_names_are_rejected() {
+        for name in ["", ".", "..", "a/b"] {
+            assert!(Layout::new(LayoutKind::Sibling, &names(&[name]), &[]).is_err());
+        }
+        assert!(Layout::new(LayoutKind::Sibling, &names(&["RAW"]), &names(&["RAW"])).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Pinning RAW libraries to their volume.
+//!
+//! An unmounted external drive leaves an empty mount point behind that passes
+//! every other check. `register` records a RAW library either with a marker file
+//! in its root or with the UUID of the filesystem it lives on, and every later
+//! run with a `--raw` inside a registered library verifies that the directory is
+//! still that library.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// The marker file written into the root of a library pinned by marker.
+const MARKER: &str = ".photo-cleanup-library";
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pin {
+    /// Write a marker file with a random id into the library root.
+    Marker,
+    /// Record the UUID of the filesystem the library is on.
+    Uuid,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Registry {
+    libraries: Vec<Library>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Library {
+    path: PathBuf,
+    pin: Pin,
+    /// The marker id or the filesystem UUID, depending on `pin`.
+    id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Marker {
+    id: String,
+}
+
+fn registry_path() -> io::Result<PathBuf> {
+    Ok(config::config_dir()?.join("libraries.json"))
+}
+
+fn read_registry() -> io::Result<Registry> {
+    match fs::read_to_string(registry_path()?) {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Registry::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_registry(registry: &Registry) -> io::Result<()> {
+    let path = registry_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut json = serde_json::to_string_pretty(registry)?;
+    json.push('\n');
+    fs::write(path, json)
+}
+
+/// Returns the UUID of the filesystem `path` is on, as listed in `/dev/disk/by-uuid`.
+fn filesystem_uuid(path: &Path) -> io::Result<Option<String>> {
+    let device = fs::metadata(path)?.dev();
+    for entry in fs::read_dir("/dev/disk/by-uuid")? {
+        let entry = entry?;
+        if fs::metadata(entry.path()).is_ok_and(|meta| meta.rdev() == device) {
+            return Ok(Some(entry.file_name().to_string_lossy().into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn random_id() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn exit_with(message: &str, e: io::Error) -> ! {
+    eprintln!("Error: {}: {}", message, e);
+    process::exit(1);
+}
+
+/// Registers the RAW library at `raw` so later runs can verify it.
+pub fn register(raw: &Path, pin: Pin) {
+    let path = fs::canonicalize(raw)
+        .unwrap_or_else(|e| exit_with(&format!("Cannot resolve {}", raw.display()), e));
+    let mut registry =
+        read_registry().unwrap_or_else(|e| exit_with("Cannot read the library registry", e));
+
+    let id = match pin {
+        Pin::Marker => {
+            let id = random_id().unwrap_or_else(|e| exit_with("Cannot generate an id", e));
+            let marker = serde_json::to_string(&Marker { id: id.clone() })
+                .map_err(io::Error::from)
+                .and_then(|json| fs::write(path.join(MARKER), json + "\n"));
+            if let Err(e) = marker {
+                exit_with(
+                    &format!("Cannot write marker file into {}", path.display()),
+                    e,
+                );
+            }
+            id
+        }
+        Pin::Uuid => match filesystem_uuid(&path) {
+            Ok(Some(uuid)) => uuid,
+            Ok(None) => {
+                eprintln!(
+                    "Error: No filesystem UUID found for {}, use --pin marker instead",
+                    path.display()
+                );
+                process::exit(1);
+            }
+            Err(e) => exit_with("Cannot determine the filesystem UUID", e),
+        },
+    };
+
+    registry.libraries.retain(|library| library.path != path);
+    registry.libraries.push(Library {
+        path: path.clone(),
+        pin,
+        id: id.clone(),
+    });
+    if let Err(e) = write_registry(&registry) {
+        exit_with("Cannot write the library registry", e);
+    }
+
+    match pin {
+        Pin::Marker => println!("Registered {} with marker id {}", path.display(), id),
+        Pin::Uuid => println!("Registered {} on filesystem UUID {}", path.display(), id),
+    }
+}
+
+/// Removes the registration of the RAW library at `raw`.
+pub fn unregister(raw: &Path) {
+    let path = fs::canonicalize(raw).unwrap_or_else(|_| raw.to_path_buf());
+    let mut registry =
+        read_registry().unwrap_or_else(|e| exit_with("Cannot read the library registry", e));
+
+    let before = registry.libraries.len();
+    registry.libraries.retain(|library| library.path != path);
+    if registry.libraries.len() == before {
+        eprintln!("Error: {} is not a registered library", path.display());
+        process::exit(1);
+    }
+    if let Err(e) = write_registry(&registry) {
+        exit_with("Cannot write the library registry", e);
+    }
+    if path.join(MARKER).is_file() {
+        println!(
+            "Unregistered {}. The marker file {} was left in place.",
+            path.display(),
+            MARKER
+        );
+    } else {
+        println!("Unregistered {}", path.display());
+    }
+}
+
+/// Exits when `raw` lies inside a registered library that it does not belong to.
+pub fn verify(raw: &Path) {
+    let Ok(raw) = fs::canonicalize(raw) else {
+        return;
+    };
+    let registry =
+        read_registry().unwrap_or_else(|e| exit_with("Cannot read the library registry", e));
+    let Some(library) = registry
+        .libraries
+        .iter()
+        .filter(|library| raw.starts_with(&library.path))
+        .max_by_key(|library| library.path.components().count())
+    else {
+        return;
+    };
+
+    let problem = match library.pin {
+        Pin::Marker => match fs::read_to_string(library.path.join(MARKER)) {
+            Ok(json) => match serde_json::from_str::<Marker>(&json) {
+                Ok(marker) if marker.id == library.id => None,
+                Ok(marker) => Some(format!(
+                    "its marker file belongs to a different library ({})",
+                    marker.id
+                )),
+                Err(e) => Some(format!("its marker file is unreadable: {e}")),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Some("its marker file is missing, is the drive mounted?".to_string())
+            }
+            Err(e) => Some(format!("its marker file cannot be read: {e}")),
+        },
+        Pin::Uuid => match filesystem_uuid(&raw) {
+            Ok(Some(uuid)) if uuid == library.id => None,
+            Ok(Some(uuid)) => Some(format!(
+                "it is on filesystem {} instead of {}, is the drive mounted?",
+                uuid, library.id
+            )),
+            Ok(None) => Some(format!(
+                "its filesystem has no UUID instead of {}, is the drive mounted?",
+                library.id
+            )),
+            Err(e) => Some(format!("its filesystem UUID cannot be determined: {e}")),
+        },
+    };
+
+    if let Some(problem) = problem {
+        eprintln!(
+            "Error: {} is not the registered RAW library {}: {}",
+            raw.display(),
+            library.path.display(),
+            problem
+        );
+        process::exit(1);
+    }
+}
@@ -4,29 +4,253 @@ use std::{
     process,
 };
 
-use clap::{Parser, Subcommand};
-use walkdir::WalkDir;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use config::Config;
+use delete::{DeleteBackend, DeleteLimits, DeleteOptions};
+use formats::{CompressedFormats, RawFormats};
+use layout::{Layout, LayoutKind};
+use mapping::{DirMapping, RuleSpec};
+use matching::{Matcher, PairBy};
+use moves::MoveOptions;
+use protect::Protection;
+use select::select_files;
+use sidecars::Registry;
+use stems::{Normalization, StemRules};
+
+mod capture;
+mod checks;
+mod config;
+mod confirm;
+mod delete;
+mod formats;
+mod fsutil;
+mod layout;
+mod library;
+mod mapping;
+mod matching;
+mod moves;
+mod plan;
+mod protect;
+mod quarantine;
+mod reconcile;
+mod relayout;
+mod select;
+mod sidecars;
+mod stems;
+mod trash;
 
 #[derive(Parser, Debug)]
 #[clap(name = "photo-cleanup")]
 #[clap(arg_required_else_help = true)]
 #[clap(version)]
 struct Args {
+    #[clap(long, global = true, value_name = "FILE")]
+    /// Read settings from this file instead of $XDG_CONFIG_HOME/photo-cleanup/config.toml.
+    config: Option<PathBuf>,
     #[clap(subcommand)]
     command: Command,
 }
 
+/// Where the RAW and compressed files are and how they are arranged.
 #[derive(Parser, Debug)]
-struct CleanArgs {
-    #[clap(short, long)]
+struct RootArgs {
+    #[clap(short, long, required_unless_present = "library")]
     /// The directory in which the raw files can be found.
-    raw: PathBuf,
-    #[clap(short, long)]
+    raw: Option<PathBuf>,
+    #[clap(short, long, required_unless_present = "library")]
     /// The directory in which the compressed files can be found.
-    compressed: PathBuf,
+    compressed: Option<PathBuf>,
+    #[clap(short, long, conflicts_with_all = ["raw", "compressed"])]
+    /// The single root of a library that holds both RAW and compressed files.
+    ///
+    /// Implies --layout mixed unless another single-root layout is given.
+    library: Option<PathBuf>,
+    #[clap(long, value_enum)]
+    /// How RAW and compressed files are arranged.
+    ///
+    /// `parallel` roots, `mixed` in the directories of one --library, or
+    /// `sibling` RAW and compressed folders in every shoot of one --library.
+    /// Defaults to the config file, and to `parallel`.
+    layout: Option<LayoutKind>,
+    #[clap(long, value_name = "NAME", value_delimiter = ',')]
+    /// The names of RAW folders in the sibling layout, tried in this order.
+    ///
+    /// Defaults to the config file, and to `RAW,Raw,raw`.
+    raw_folders: Vec<String>,
+    #[clap(long, value_name = "NAME", value_delimiter = ',')]
+    /// The names of compressed folders in the sibling layout.
+    ///
+    /// Defaults to the config file, and to `JPG,Jpg,jpg,JPEG,Jpeg,jpeg`.
+    compressed_folders: Vec<String>,
+}
+
+impl RootArgs {
+    /// The RAW root, which is the library root in single-root layouts.
+    fn raw_root(&self) -> &Path {
+        self.raw
+            .as_deref()
+            .or(self.library.as_deref())
+            .expect("required by clap")
+    }
+
+    /// The compressed root, which is the library root in single-root layouts.
+    fn compressed_root(&self) -> &Path {
+        self.compressed
+            .as_deref()
+            .or(self.library.as_deref())
+            .expect("required by clap")
+    }
+}
+
+/// Which files count as RAW and as compressed.
+#[derive(Parser, Debug)]
+struct FormatArgs {
+    #[clap(
+        long,
+        value_name = "SPEC",
+        value_delimiter = ',',
+        allow_hyphen_values = true
+    )]
+    /// Choose the RAW extensions, e.g. `fujifilm`, `raf,dng`, `+pef` or `-raw`.
+    ///
+    /// A vendor name stands for all its extensions. Plain entries restrict the
+    /// set to just those, `+` adds and `-` removes. Applied after the config file.
+    raw_ext: Vec<String>,
+    #[clap(
+        long,
+        value_name = "SPEC",
+        value_delimiter = ',',
+        allow_hyphen_values = true
+    )]
+    /// Choose the compressed formats to act on, e.g. `jpeg`, `jpeg,heif`, `+heif` or `+jpe`.
+    ///
+    /// Defaults to JPEG. Known formats are jpeg, heif, avif, jxl, png,
+    /// tiff and webp; other entries are taken as extensions. Uses the same
+    /// syntax as --raw-ext and is applied after the config file.
+    compressed_ext: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct ScanArgs {
+    #[clap(flatten)]
+    roots: RootArgs,
+    #[clap(flatten)]
+    formats: FormatArgs,
+    #[clap(long)]
+    /// Abort when any part of either directory tree cannot be read.
+    abort_on_walk_error: bool,
+    #[clap(long)]
+    /// Match a RAW with the same stem anywhere in the raw directory, not only at the same relative path.
+    ///
+    /// Files whose stem appears in several RAW directories are reported as
+    /// ambiguous and never deleted.
+    match_anywhere: bool,
+    #[clap(long)]
+    /// Let clean-matched delete files with several candidate RAWs.
+    ///
+    /// Candidates come from --match-anywhere or --pair-by exif.
+    allow_ambiguous: bool,
+    #[clap(long, value_enum, default_value_t = PairBy::Stem)]
+    /// How to pair compressed files with RAWs.
+    ///
+    /// `exif` compares DateTimeOriginal, SubSecTimeOriginal, BodySerialNumber
+    /// and ImageUniqueID, so renamed files still pair up.
+    pair_by: PairBy,
+    #[clap(long, value_name = "PRESET", value_delimiter = ',')]
+    /// Strip the suffixes of editor presets from stems that have no RAW.
+    ///
+    /// Presets are lightroom (-Edit, -Enhanced-NR, ...), darktable (_01),
+    /// capture-one (_1) and copies (" (2)", " copy", -2). Added to the presets
+    /// of the config file.
+    stem_preset: Vec<String>,
+    #[clap(long, value_name = "REGEX", allow_hyphen_values = true)]
+    /// Strip suffixes matching this regex from stems that have no RAW, may be repeated.
+    stem_pattern: Vec<String>,
+    #[clap(long, value_name = "FORM", value_enum)]
+    /// Compare stems under Unicode normalization, `nfc` or `nfd`.
+    ///
+    /// Matches names whose accents are composed on one side and decomposed on
+    /// the other, as on files copied from macOS volumes. Stem patterns see
+    /// the stem in this form. Overrides the config file.
+    normalize_stems: Option<Normalization>,
+}
+
+#[derive(Parser, Debug)]
+struct DeleteArgs {
     #[clap(long)]
     /// Do not delete files and instead output which files would be deleted.
     dry: bool,
+    #[clap(long)]
+    /// Move files to the freedesktop.org Trash instead of deleting them.
+    trash: bool,
+    #[clap(long, value_name = "DIR", conflicts_with = "trash")]
+    /// Move files into a dated run folder below this directory instead of deleting them.
+    quarantine: Option<PathBuf>,
+    #[clap(long, value_name = "PERCENT", value_parser = parse_percent)]
+    /// Refuse to delete more than this share of the scanned files without --force.
+    ///
+    /// Defaults to 50 when deleting orphaned files and to no limit otherwise.
+    max_delete_percent: Option<f64>,
+    #[clap(long, value_name = "COUNT")]
+    /// Refuse to delete more than this many files without --force.
+    max_delete_count: Option<usize>,
+    #[clap(short, long)]
+    /// Do not ask for confirmation before deleting (required when stdin is not a terminal).
+    yes: bool,
+    #[clap(long)]
+    /// Delete even when the deletion limits are exceeded or the directories look misconfigured.
+    force: bool,
+    #[clap(long)]
+    /// Leave the sidecars of deleted files (XMP, .pp3, .dop, .on1, .cos) in place.
+    keep_sidecars: bool,
+}
+
+#[derive(Parser, Debug)]
+struct CleanArgs {
+    #[clap(flatten)]
+    scan: ScanArgs,
+    #[clap(flatten)]
+    delete: DeleteArgs,
+    #[clap(short, long)]
+    /// Print detailed output for each file operation.
+    verbose: bool,
+    #[clap(long)]
+    /// Print only summary output (suppresses per-file logs and dry-run lists).
+    summary_only: bool,
+    #[clap(long, conflicts_with = "keep_sidecars")]
+    /// Also remove sidecars in either directory whose image exists in neither.
+    clean_sidecars: bool,
+}
+
+#[derive(Parser, Debug)]
+struct PlanArgs {
+    #[clap(flatten)]
+    scan: ScanArgs,
+    #[clap(short, long, value_enum)]
+    /// Which files to plan for deletion.
+    mode: DeleteMode,
+    #[clap(short, long)]
+    /// The file to write the plan to.
+    output: PathBuf,
+    #[clap(long)]
+    /// Plan even when the raw or compressed directory looks misconfigured.
+    force: bool,
+    #[clap(short, long)]
+    /// Print detailed output for each file.
+    verbose: bool,
+    #[clap(long)]
+    /// Print only summary output (suppresses per-file logs).
+    summary_only: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ApplyArgs {
+    /// The plan file written by `plan`.
+    plan: PathBuf,
+    #[clap(flatten)]
+    delete: DeleteArgs,
     #[clap(short, long)]
     /// Print detailed output for each file operation.
     verbose: bool,
@@ -35,232 +259,783 @@ struct CleanArgs {
     summary_only: bool,
 }
 
+#[derive(Parser, Debug)]
+struct RegisterArgs {
+    #[clap(short, long)]
+    /// The root directory of the RAW library.
+    raw: PathBuf,
+    #[clap(long, value_enum, default_value_t = library::Pin::Marker)]
+    /// How to recognize the library later.
+    pin: library::Pin,
+}
+
+#[derive(Parser, Debug)]
+struct UnregisterArgs {
+    #[clap(short, long)]
+    /// The root directory of the RAW library.
+    raw: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct RestoreArgs {
+    #[clap(short, long, value_name = "DIR")]
+    /// The quarantine directory the run was written to.
+    quarantine: PathBuf,
+    /// The id of the run to restore, i.e. its folder name.
+    run_id: String,
+    #[clap(long)]
+    /// Do not move files and instead output which files would be restored.
+    dry: bool,
+    #[clap(short, long)]
+    /// Print detailed output for each file operation.
+    verbose: bool,
+}
+
+#[derive(Parser, Debug)]
+struct PurgeArgs {
+    #[clap(short, long, value_name = "DIR")]
+    /// The quarantine directory to purge runs from.
+    quarantine: PathBuf,
+    #[clap(long, value_name = "DAYS")]
+    /// Purge runs created more than this many days ago.
+    older_than: u32,
+    #[clap(long)]
+    /// Do not delete runs and instead output which runs would be purged.
+    dry: bool,
+    #[clap(short, long)]
+    /// Print detailed output for each run.
+    verbose: bool,
+}
+
+#[derive(Parser, Debug)]
+struct TestMappingArgs {
+    #[clap(required = true)]
+    /// Paths of compressed files relative to the compressed directory, e.g. `2024/2024-05-01 Trip/DSCF1234.jpg`.
+    samples: Vec<PathBuf>,
+    #[clap(long, conflicts_with = "template", requires = "to")]
+    /// Test this regex rule instead of the configured ones.
+    regex: Option<String>,
+    #[clap(long, requires = "to")]
+    /// Test this template rule instead of the configured ones.
+    template: Option<String>,
+    #[clap(long)]
+    /// The RAW directory of the rule given with --regex or --template.
+    to: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct RelayoutArgs {
+    #[clap(flatten)]
+    roots: RootArgs,
+    #[clap(flatten)]
+    formats: FormatArgs,
+    #[clap(long, value_enum)]
+    /// The layout to move the files into.
+    ///
+    /// In the `sibling` layout, the directory of a file becomes its shoot and
+    /// the file goes into the first of --raw-folders or --compressed-folders.
+    to: LayoutKind,
+    #[clap(long, value_name = "DIR", requires = "to_compressed")]
+    /// The RAW root to move RAW files into, for --to parallel.
+    to_raw: Option<PathBuf>,
+    #[clap(long, value_name = "DIR", requires = "to_raw")]
+    /// The compressed root to move compressed files into, for --to parallel.
+    to_compressed: Option<PathBuf>,
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["to_raw", "to_compressed"])]
+    /// The library to move all files into, for --to mixed or sibling.
+    ///
+    /// Defaults to the --library the files come from.
+    to_library: Option<PathBuf>,
+    #[clap(long)]
+    /// Do not move files and instead output where they would go.
+    dry: bool,
+    #[clap(short, long)]
+    /// Print every file that is moved.
+    verbose: bool,
+    #[clap(long, value_name = "FILE")]
+    /// Record the moves in this file instead of relayout-<date>.jsonl in the working directory.
+    manifest: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct ReconcileArgs {
+    #[clap(flatten)]
+    scan: ScanArgs,
+    #[clap(long)]
+    /// Do not move files and instead output where they would go.
+    dry: bool,
+    #[clap(short, long)]
+    /// Print every compressed file and what happens to it.
+    verbose: bool,
+    #[clap(long, value_name = "FILE")]
+    /// Record the moves in this file instead of reconcile-<date>.jsonl in the working directory.
+    manifest: Option<PathBuf>,
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
-    /// Deletes all JPEG images that have no matching RAW file.
+    /// Deletes all compressed images that have no matching RAW file.
     ///
     /// Matching files are identified by relative path and file name.
     Clean(CleanArgs),
-    /// Deletes all JPEG images that do have a matching RAW file.
+    /// Deletes all compressed images that do have a matching RAW file.
     ///
     /// Matching files are identified by relative path and file name.
     CleanMatched(CleanArgs),
+    /// Deletes all RAW files that have no matching compressed image.
+    ///
+    /// The reverse of `clean`, for culling on the compressed images: a RAW is
+    /// kept when any compressed file matches it under the usual rules.
+    CleanRaw(CleanArgs),
+    /// Deletes all RAW files that do have a matching compressed image.
+    ///
+    /// For keeping only the compressed images, e.g. of a camera card that
+    /// wrote both. RAWs that are one of several candidates are never deleted.
+    CleanRawMatched(CleanArgs),
+    /// Writes the files that would be deleted to a plan file for review.
+    ///
+    /// Each entry records the file's size, modification time and inode, and the
+    /// RAW file that matched it.
+    Plan(PlanArgs),
+    /// Deletes the files of a plan written by `plan`.
+    ///
+    /// Every entry is re-checked right before deletion and skipped if the file or
+    /// the RAW situation changed since the plan was written.
+    Apply(ApplyArgs),
+    /// Registers a RAW library so runs refuse to use a different directory in its place.
+    ///
+    /// Later runs whose --raw lies inside the library check its marker file or
+    /// filesystem UUID, e.g. to catch an unmounted drive.
+    Register(RegisterArgs),
+    /// Removes the registration of a RAW library.
+    Unregister(UnregisterArgs),
+    /// Moves the files of a quarantine run back to their original locations.
+    Restore(RestoreArgs),
+    /// Permanently deletes quarantine runs older than a given age.
+    Purge(PurgeArgs),
+    /// Shows which RAW directory sample paths map to under the mapping rules.
+    ///
+    /// Uses the rules of the config file, or a single rule given with --regex
+    /// or --template and --to.
+    TestMapping(TestMappingArgs),
+    /// Moves a library from one layout into another.
+    ///
+    /// RAW and compressed files keep their relative paths and take their
+    /// sidecars along. Every move is recorded in a manifest.
+    Relayout(RelayoutArgs),
+    /// Moves compressed images next to RAW files that were moved elsewhere.
+    ///
+    /// Finds the RAW of each compressed file anywhere below --raw and moves
+    /// the file to the mirrored path. Ambiguous files and conflicts are only
+    /// reported, and nothing is deleted.
+    Reconcile(ReconcileArgs),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum DeleteMode {
+    /// Compressed files without a matching RAW file.
     Orphaned,
+    /// Compressed files with a matching RAW file.
     Matched,
+    /// RAW files without a matching compressed file.
+    #[serde(rename = "raw-orphaned")]
+    RawOrphaned,
+    /// RAW files with a matching compressed file.
+    #[serde(rename = "raw-matched")]
+    RawMatched,
 }
 
 fn main() {
     let args = Args::parse();
+    let config = config::load(args.config.as_deref());
 
     match args.command {
         Command::Clean(clean_args) => {
-            run_clean(clean_args, DeleteMode::Orphaned);
+            run_clean(clean_args, &config, DeleteMode::Orphaned);
         }
         Command::CleanMatched(clean_args) => {
-            run_clean(clean_args, DeleteMode::Matched);
+            run_clean(clean_args, &config, DeleteMode::Matched);
+        }
+        Command::CleanRaw(clean_args) => {
+            run_clean(clean_args, &config, DeleteMode::RawOrphaned);
+        }
+        Command::CleanRawMatched(clean_args) => {
+            run_clean(clean_args, &config, DeleteMode::RawMatched);
+        }
+        Command::Plan(plan_args) => {
+            run_plan(plan_args, &config);
+        }
+        Command::Apply(apply_args) => {
+            // The roots are only known once the plan is read, so `apply` checks
+            // the quarantine directory itself.
+            let options = delete_options(
+                apply_args.delete,
+                &config,
+                &[],
+                apply_args.verbose,
+                apply_args.summary_only,
+            );
+            plan::apply(&apply_args.plan, &options);
+        }
+        Command::Register(register_args) => {
+            library::register(&register_args.raw, register_args.pin);
+        }
+        Command::Unregister(unregister_args) => {
+            library::unregister(&unregister_args.raw);
+        }
+        Command::Restore(restore_args) => {
+            quarantine::restore(
+                &restore_args.quarantine,
+                &restore_args.run_id,
+                restore_args.dry,
+                restore_args.verbose,
+            );
+        }
+        Command::TestMapping(test_args) => {
+            run_test_mapping(test_args, &config);
+        }
+        Command::Relayout(relayout_args) => {
+            run_relayout(relayout_args, &config);
+        }
+        Command::Reconcile(reconcile_args) => {
+            run_reconcile(reconcile_args, &config);
+        }
+        Command::Purge(purge_args) => {
+            quarantine::purge(
+                &purge_args.quarantine,
+                purge_args.older_than,
+                purge_args.dry,
+                purge_args.verbose,
+            );
         }
     }
 }
 
-fn run_clean(clean_args: CleanArgs, mode: DeleteMode) {
+fn run_clean(clean_args: CleanArgs, config: &Config, mode: DeleteMode) {
     let CleanArgs {
-        raw,
-        compressed,
-        dry,
+        scan,
+        delete,
         verbose,
         summary_only,
+        clean_sidecars,
     } = clean_args;
 
-    if !raw.exists() {
-        eprintln!("Error: Raw directory does not exist: {}", raw.display());
-        process::exit(1);
+    let matcher = build_matcher(&scan, config);
+    checks::check_roots(
+        scan.roots.raw_root(),
+        scan.roots.compressed_root(),
+        &matcher,
+        mode,
+        delete.force,
+    );
+    let options = delete_options(
+        delete,
+        config,
+        &[scan.roots.raw_root(), scan.roots.compressed_root()],
+        verbose,
+        summary_only,
+    );
+
+    let removed = clean_photos(&scan, &matcher, mode, &options);
+    if clean_sidecars {
+        clean_orphan_sidecars(&scan, &matcher, &options, removed);
     }
-    if !raw.is_dir() {
-        eprintln!("Error: Raw path is not a directory: {}", raw.display());
-        process::exit(1);
+}
+
+fn run_plan(plan_args: PlanArgs, config: &Config) {
+    let PlanArgs {
+        mut scan,
+        mode,
+        output,
+        force,
+        verbose,
+        summary_only,
+    } = plan_args;
+
+    let matcher = build_matcher(&scan, config);
+    checks::check_roots(
+        scan.roots.raw_root(),
+        scan.roots.compressed_root(),
+        &matcher,
+        mode,
+        force,
+    );
+    match (
+        fs::canonicalize(scan.roots.raw_root()),
+        fs::canonicalize(scan.roots.compressed_root()),
+    ) {
+        (Ok(raw), Ok(compressed)) => {
+            scan.roots.raw = Some(raw);
+            scan.roots.compressed = Some(compressed);
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Error: Cannot resolve directories: {}", e);
+            process::exit(1);
+        }
     }
-    if !compressed.exists() {
-        eprintln!(
-            "Error: Compressed directory does not exist: {}",
-            compressed.display()
-        );
+
+    let registry = config_registry(config);
+
+    let selection = select_files(&scan, &matcher, mode, verbose, summary_only);
+    match plan::write_plan(
+        scan.roots.raw_root(),
+        scan.roots.compressed_root(),
+        &matcher,
+        mode,
+        &selection,
+        &registry,
+        &output,
+    ) {
+        Ok(()) => println!(
+            "\nWrote plan with {} files to {}",
+            selection.to_delete.len(),
+            output.display()
+        ),
+        Err(e) => {
+            eprintln!("Error: Cannot write plan {}: {}", output.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_relayout(relayout_args: RelayoutArgs, config: &Config) {
+    let RelayoutArgs {
+        roots,
+        formats,
+        to,
+        to_raw,
+        to_compressed,
+        to_library,
+        dry,
+        verbose,
+        manifest,
+    } = relayout_args;
+
+    let (raw_formats, compressed_formats) = build_formats(&formats, config);
+    let layout = build_layout(&roots, config);
+    if layout.kind != LayoutKind::Sibling
+        && to != LayoutKind::Sibling
+        && !(roots.raw_folders.is_empty() && roots.compressed_folders.is_empty())
+    {
+        eprintln!("Error: --raw-folders and --compressed-folders need a sibling layout");
         process::exit(1);
     }
-    if !compressed.is_dir() {
-        eprintln!(
-            "Error: Compressed path is not a directory: {}",
-            compressed.display()
-        );
+    let registry = config_registry(config);
+    for root in [roots.raw_root(), roots.compressed_root()] {
+        checks::check_directory("Source", root);
+    }
+
+    let (target_raw, target_compressed) = match (to, to_raw, to_compressed) {
+        (LayoutKind::Parallel, Some(to_raw), Some(to_compressed)) => {
+            if to_raw.starts_with(&to_compressed) || to_compressed.starts_with(&to_raw) {
+                eprintln!(
+                    "Error: --to-raw and --to-compressed must not be the same or nested inside each other"
+                );
+                process::exit(1);
+            }
+            (to_raw, to_compressed)
+        }
+        (LayoutKind::Parallel, _, _) => {
+            eprintln!("Error: --to parallel needs --to-raw and --to-compressed");
+            process::exit(1);
+        }
+        (_, None, None) => match to_library.or(roots.library.clone()) {
+            Some(library) => (library.clone(), library),
+            None => {
+                eprintln!("Error: --to {} needs --to-library", to.name());
+                process::exit(1);
+            }
+        },
+        (_, _, _) => {
+            eprintln!(
+                "Error: --to {} takes --to-library instead of --to-raw and --to-compressed",
+                to.name()
+            );
+            process::exit(1);
+        }
+    };
+    let target = relayout::Tree {
+        layout: Layout {
+            kind: to,
+            ..layout.clone()
+        },
+        raw_root: target_raw,
+        compressed_root: target_compressed,
+    };
+    let source = relayout::Tree {
+        layout,
+        raw_root: roots.raw_root().to_path_buf(),
+        compressed_root: roots.compressed_root().to_path_buf(),
+    };
+    if source.layout.kind == target.layout.kind
+        && source.raw_root == target.raw_root
+        && source.compressed_root == target.compressed_root
+    {
+        eprintln!("Error: The files are already in the {} layout", to.name());
         process::exit(1);
     }
 
-    clean_photos(&raw, &compressed, dry, verbose, summary_only, mode);
+    relayout::relayout(
+        &source,
+        &target,
+        &raw_formats,
+        &compressed_formats,
+        &registry,
+        &MoveOptions {
+            dry_run: dry,
+            verbose,
+            manifest,
+        },
+    );
 }
 
-fn is_jpeg(path: &Path) -> bool {
-    if let Some(ext) = path.extension() {
-        let ext_lower = ext.to_string_lossy().to_lowercase();
-        ext_lower == "jpg" || ext_lower == "jpeg"
-    } else {
-        false
-    }
+fn run_reconcile(reconcile_args: ReconcileArgs, config: &Config) {
+    let ReconcileArgs {
+        mut scan,
+        dry,
+        verbose,
+        manifest,
+    } = reconcile_args;
+
+    // Moved RAWs are no longer at the mirrored path.
+    scan.match_anywhere = true;
+    let matcher = build_matcher(&scan, config);
+    // Nothing is deleted, so the checks are those of the most lenient mode.
+    checks::check_roots(
+        scan.roots.raw_root(),
+        scan.roots.compressed_root(),
+        &matcher,
+        DeleteMode::Matched,
+        false,
+    );
+    let registry = config_registry(config);
+
+    reconcile::reconcile(
+        &matcher,
+        scan.roots.raw_root(),
+        scan.roots.compressed_root(),
+        &registry,
+        &MoveOptions {
+            dry_run: dry,
+            verbose,
+            manifest,
+        },
+    );
 }
 
-fn get_jpeg_files(compressed_root: &Path) -> Vec<PathBuf> {
-    let mut jpeg_files = Vec::new();
+fn run_test_mapping(test_args: TestMappingArgs, config: &Config) {
+    let TestMappingArgs {
+        samples,
+        regex,
+        template,
+        to,
+    } = test_args;
 
-    for entry in WalkDir::new(compressed_root)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if entry.file_type().is_file() && is_jpeg(entry.path()) {
-            jpeg_files.push(entry.path().to_path_buf());
+    let mapping = match to {
+        Some(to) if regex.is_some() || template.is_some() => DirMapping::new(vec![RuleSpec {
+            regex,
+            template,
+            to,
+        }])
+        .unwrap_or_else(|e| {
+            eprintln!("Error: Invalid mapping: {}", e);
+            process::exit(1);
+        }),
+        Some(_) => {
+            eprintln!("Error: --to needs --regex or --template");
+            process::exit(1);
         }
-    }
-
-    jpeg_files
+        None => {
+            let mapping = config_mapping(config);
+            if mapping.is_empty() {
+                eprintln!("Error: The config file has no mapping rules");
+                process::exit(1);
+            }
+            mapping
+        }
+    };
+    mapping::test_samples(&mapping, &samples);
 }
 
-fn find_matching_raw(
-    compressed_file: &Path,
-    compressed_root: &Path,
-    raw_root: &Path,
-) -> Option<PathBuf> {
-    let relative_path = compressed_file.strip_prefix(compressed_root).ok()?;
+/// Builds the matcher from the config file and the command line, exiting on invalid specs.
+fn build_matcher(scan: &ScanArgs, config: &Config) -> Matcher {
+    let (raw_formats, compressed_formats) = build_formats(&scan.formats, config);
 
-    let parent_dir = relative_path.parent()?;
-    let file_stem = compressed_file.file_stem()?;
+    let presets = [config.stems.presets.as_slice(), &scan.stem_preset].concat();
+    let patterns = [config.stems.patterns.as_slice(), &scan.stem_pattern].concat();
+    let stems = StemRules::new(&presets, &patterns).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
 
-    let raw_dir = raw_root.join(parent_dir);
+    let layout = build_layout(&scan.roots, config);
+    if layout.kind != LayoutKind::Sibling
+        && !(scan.roots.raw_folders.is_empty() && scan.roots.compressed_folders.is_empty())
+    {
+        eprintln!("Error: --raw-folders and --compressed-folders need --layout sibling");
+        process::exit(1);
+    }
 
-    if !raw_dir.exists() {
-        return None;
+    Matcher {
+        raw_formats,
+        compressed_formats,
+        match_anywhere: scan.match_anywhere,
+        mapping: config_mapping(config),
+        pair_by: scan.pair_by,
+        stems,
+        normalize_stems: scan.normalize_stems.or(config.stems.normalize),
+        layout,
     }
+}
 
-    let raw_extensions = [
-        "raf", "cr2", "cr3", "nef", "arw", "dng", "orf", "rw2", "raw",
-    ];
+/// Builds the RAW extensions and compressed formats from the config file and
+/// the flags, exiting on invalid specs.
+fn build_formats(formats: &FormatArgs, config: &Config) -> (RawFormats, CompressedFormats) {
+    let mut raw_formats = RawFormats::builtin();
+    if let Err(e) = raw_formats.apply(&config.raw.extensions) {
+        eprintln!("Error: Invalid RAW extensions in the config file: {}", e);
+        process::exit(1);
+    }
+    if let Err(e) = raw_formats.apply(&formats.raw_ext) {
+        eprintln!("Error: Invalid --raw-ext: {}", e);
+        process::exit(1);
+    }
+    if raw_formats.is_empty() {
+        eprintln!("Error: No RAW extensions are left to match against");
+        process::exit(1);
+    }
 
-    for ext in &raw_extensions {
-        let potential_raw = raw_dir.join(format!("{}.{}", file_stem.to_string_lossy(), ext));
-        if potential_raw.exists() {
-            return Some(potential_raw);
-        }
-        let potential_raw_upper = raw_dir.join(format!(
-            "{}.{}",
-            file_stem.to_string_lossy(),
-            ext.to_uppercase()
-        ));
-        if potential_raw_upper.exists() {
-            return Some(potential_raw_upper);
+    let mut compressed_formats = CompressedFormats::builtin();
+    if let Err(e) = compressed_formats.apply(&config.compressed.extensions) {
+        eprintln!(
+            "Error: Invalid compressed extensions in the config file: {}",
+            e
+        );
+        process::exit(1);
+    }
+    if let Err(e) = compressed_formats.apply(&formats.compressed_ext) {
+        eprintln!("Error: Invalid --compressed-ext: {}", e);
+        process::exit(1);
+    }
+    if compressed_formats.is_empty() {
+        eprintln!("Error: No compressed formats are left to act on");
+        process::exit(1);
+    }
+
+    (raw_formats, compressed_formats)
+}
+
+/// Builds the layout of `roots` from the flags and the config file, exiting
+/// when it does not fit the roots that were given.
+fn build_layout(roots: &RootArgs, config: &Config) -> Layout {
+    let kind = roots
+        .layout
+        .or(config.layout.kind)
+        .unwrap_or(match roots.library {
+            Some(_) => LayoutKind::Mixed,
+            None => LayoutKind::Parallel,
+        });
+    let pick = |flag: &[String], config: &[String]| {
+        if flag.is_empty() {
+            config.to_vec()
+        } else {
+            flag.to_vec()
         }
+    };
+    let layout = Layout::new(
+        kind,
+        &pick(&roots.raw_folders, &config.layout.raw_folders),
+        &pick(&roots.compressed_folders, &config.layout.compressed_folders),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    if layout.is_single_root() && roots.library.is_none() {
+        eprintln!(
+            "Error: The {} layout takes a single --library directory instead of --raw and --compressed",
+            kind.name()
+        );
+        process::exit(1);
     }
+    if !layout.is_single_root() && roots.library.is_some() {
+        eprintln!("Error: --library needs a single-root layout, e.g. --layout mixed");
+        process::exit(1);
+    }
+    layout
+}
+
+/// Compiles the mapping rules of the config file, exiting on invalid rules.
+fn config_mapping(config: &Config) -> DirMapping {
+    DirMapping::new(config.mapping.clone()).unwrap_or_else(|e| {
+        eprintln!("Error: Invalid mapping in the config file: {}", e);
+        process::exit(1);
+    })
+}
 
-    None
+fn config_registry(config: &Config) -> Registry {
+    Registry::new(&config.sidecars).unwrap_or_else(|e| {
+        eprintln!("Error: Invalid sidecars in the config file: {}", e);
+        process::exit(1);
+    })
 }
 
-fn clean_photos(
-    raw_root: &Path,
-    compressed_root: &Path,
-    dry_run: bool,
+/// Builds the deletion options, refusing a quarantine directory inside any of `roots`.
+fn delete_options(
+    delete: DeleteArgs,
+    config: &Config,
+    roots: &[&Path],
     verbose: bool,
     summary_only: bool,
-    mode: DeleteMode,
-) {
-    println!(
-        "Scanning for JPEG files in {}...",
-        compressed_root.display()
-    );
+) -> DeleteOptions {
+    let DeleteArgs {
+        dry,
+        trash,
+        quarantine,
+        max_delete_percent,
+        max_delete_count,
+        yes,
+        force,
+        keep_sidecars,
+    } = delete;
 
-    let jpeg_files = get_jpeg_files(compressed_root);
-    println!("Found {} JPEG files", jpeg_files.len());
-
-    let mut to_delete = Vec::new();
-    let mut errors = Vec::new();
-    let mut matched_count = 0usize;
-
-    for jpeg_file in &jpeg_files {
-        match find_matching_raw(jpeg_file, compressed_root, raw_root) {
-            Some(raw_file) => {
-                matched_count += 1;
-                if verbose && !summary_only {
-                    println!("MATCH {} -> {}", jpeg_file.display(), raw_file.display());
-                }
-                if matches!(mode, DeleteMode::Matched) {
-                    to_delete.push(jpeg_file.clone());
-                }
-            }
-            None => {
-                if verbose && !summary_only {
-                    println!("NO_MATCH {}", jpeg_file.display());
-                }
-                if matches!(mode, DeleteMode::Orphaned) {
-                    to_delete.push(jpeg_file.clone());
-                }
+    let backend = match quarantine {
+        Some(quarantine_dir) => {
+            if roots
+                .iter()
+                .any(|root| fsutil::is_within(&quarantine_dir, root))
+            {
+                eprintln!(
+                    "Error: Quarantine directory must not be inside the raw or compressed directory: {}",
+                    quarantine_dir.display()
+                );
+                process::exit(1);
             }
+            DeleteBackend::Quarantine(quarantine_dir)
         }
-    }
+        None if trash => DeleteBackend::Trash,
+        None => DeleteBackend::Remove,
+    };
 
-    let unmatched_count = jpeg_files.len().saturating_sub(matched_count);
+    // The config is checked even when sidecars are kept in place.
+    let registry = config_registry(config);
+    let sidecars = (!keep_sidecars).then_some(registry);
 
-    println!("\nSummary:");
-    println!("  Total JPEG files: {}", jpeg_files.len());
-    println!("  Files with matching RAW: {}", matched_count);
-    println!("  Files without matching RAW: {}", unmatched_count);
+    DeleteOptions {
+        backend,
+        dry_run: dry,
+        assume_yes: yes,
+        verbose,
+        summary_only,
+        sidecars,
+        limits: DeleteLimits {
+            max_percent: max_delete_percent,
+            max_count: max_delete_count,
+            force,
+        },
+    }
+}
 
-    match mode {
-        DeleteMode::Orphaned => println!("  Deleting orphaned JPEGs (no RAW)."),
-        DeleteMode::Matched => println!("  Deleting matched JPEGs (has RAW)."),
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent: f64 = value
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("`{value}` is not a number"))?;
+    if (0.0..=100.0).contains(&percent) {
+        Ok(percent)
+    } else {
+        Err("must be between 0 and 100".to_string())
     }
+}
 
-    if to_delete.is_empty() {
-        match mode {
-            DeleteMode::Orphaned => {
-                println!("\nNo files to delete. All JPEGs have corresponding RAW files.");
-            }
-            DeleteMode::Matched => {
-                println!("\nNo files to delete. No JPEGs have corresponding RAW files.");
-            }
-        }
+/// Selects and deletes the compressed files, returning the removed paths.
+fn clean_photos(
+    scan: &ScanArgs,
+    matcher: &Matcher,
+    mode: DeleteMode,
+    options: &DeleteOptions,
+) -> Vec<PathBuf> {
+    let selection = select_files(scan, matcher, mode, options.verbose, options.summary_only);
+    if selection.to_delete.is_empty() {
+        return Vec::new();
+    }
+    let mut summary = selection.counts.summary(mode);
+    summary.push(("Unreadable paths", selection.unreadable));
+    let root = match mode {
+        DeleteMode::Orphaned | DeleteMode::Matched => scan.roots.compressed_root(),
+        DeleteMode::RawOrphaned | DeleteMode::RawMatched => scan.roots.raw_root(),
+    };
+    delete::enforce_limits(
+        selection.to_delete.len(),
+        selection.counts.total,
+        mode,
+        &summary,
+        options,
+    );
+
+    let files: Vec<PathBuf> = selection.to_delete.into_iter().map(|c| c.path).collect();
+    delete::delete_files(&files, root, options, |_| Ok(()))
+}
+
+/// Removes the sidecars in both trees whose image exists in neither.
+///
+/// `removed` are the paths the run removed, or would have in dry-run mode.
+fn clean_orphan_sidecars(
+    scan: &ScanArgs,
+    matcher: &Matcher,
+    options: &DeleteOptions,
+    removed: Vec<PathBuf>,
+) {
+    // --clean-sidecars conflicts with --keep-sidecars, so the registry is set.
+    let Some(registry) = &options.sidecars else {
+        return;
+    };
+    println!("\nLooking for sidecars without an image...");
+    let removed = removed.into_iter().collect();
+    let mut orphans = sidecars::find_orphans(
+        matcher,
+        registry,
+        scan.roots.raw_root(),
+        scan.roots.compressed_root(),
+        &removed,
+    );
+    if !orphans.walk_errors.is_empty() {
+        eprintln!("\nWarning: Not removing sidecars, these paths could not be read:");
+        select::print_walk_errors(&orphans.walk_errors);
         return;
     }
 
-    if dry_run {
-        if summary_only {
-            println!(
-                "\nDry run mode - {} files would be deleted.",
-                to_delete.len()
-            );
-        } else {
-            println!("\nDry run mode - files that would be deleted:");
-            for file in &to_delete {
-                println!("  {}", file.display());
-            }
-        }
+    let options = DeleteOptions {
+        sidecars: None,
+        ..options.clone()
+    };
+    // A single-root library is one tree, whose orphans are all reported as compressed.
+    let trees = if matcher.layout.is_single_root() {
+        vec![(
+            scan.roots.compressed_root(),
+            &mut orphans.compressed,
+            "library",
+        )]
     } else {
-        println!("\nDeleting {} files...", to_delete.len());
-        for file in &to_delete {
-            match fs::remove_file(file) {
-                Ok(_) => {
-                    if verbose && !summary_only {
-                        println!("  Deleted: {}", file.display());
-                    }
-                }
-                Err(e) => {
-                    eprintln!("  Error deleting {}: {}", file.display(), e);
-                    errors.push(file.clone());
-                }
-            }
-        }
-
-        if !errors.is_empty() {
-            eprintln!("\nEncountered {} errors during deletion", errors.len());
-        } else {
-            println!("\nSuccessfully deleted all {} files", to_delete.len());
+        vec![
+            (
+                scan.roots.compressed_root(),
+                &mut orphans.compressed,
+                "compressed",
+            ),
+            (scan.roots.raw_root(), &mut orphans.raw, "raw"),
+        ]
+    };
+    for (root, orphans, tree) in trees {
+        Protection::new(root).drop_protected(orphans, options.verbose && !options.summary_only);
+        println!(
+            "Sidecars without an image in the {} directory: {}",
+            tree,
+            orphans.len()
+        );
+        if !orphans.is_empty() {
+            delete::delete_files(orphans, root, &options, |_| Ok(()));
         }
     }
 }
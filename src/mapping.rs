@@ -0,0 +1,401 @@
+//! Rewrite rules from directories of the compressed tree to directories of the RAW tree.
+//!
+//! Without rules, a compressed file is looked up in the same relative directory
+//! below the RAW root. A rule rewrites that directory first, e.g. from
+//! `2024/2024-05-01 Trip` to `2024/05/01`. Rules are either regexes with a
+//! replacement in `regex` syntax (`$1`, `${name}`) or templates:
+//!
+//! - `{name}` captures one or more characters of a path component, and a
+//!   repeated `{name}` has to capture the same text again,
+//! - `{*}` matches any characters of a path component,
+//! - `{**}` matches any characters including `/`.
+//!
+//! Patterns have to match the whole directory. The first rule that matches wins.
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// A rule as written in the config file or on the command line.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The RAW directory, with `$1`/`${name}` for regexes and `{name}` for templates.
+    pub to: String,
+}
+
+/// A compiled rule.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "RuleSpec", into = "RuleSpec")]
+pub struct Rule {
+    spec: RuleSpec,
+    pattern: Regex,
+    /// The replacement in `regex` syntax.
+    replacement: String,
+    /// Capture groups that have to equal another group, from repeated template names.
+    repeats: Vec<(String, String)>,
+}
+
+impl From<Rule> for RuleSpec {
+    fn from(rule: Rule) -> RuleSpec {
+        rule.spec
+    }
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = String;
+
+    fn try_from(spec: RuleSpec) -> Result<Rule, String> {
+        let (pattern, replacement, repeats) = match (&spec.regex, &spec.template) {
+            (Some(regex), None) => (format!("^(?:{regex})$"), spec.to.clone(), Vec::new()),
+            (None, Some(template)) => {
+                let template = compile_template(template)?;
+                let replacement = compile_target(&spec.to, &template.names)?;
+                (template.pattern, replacement, template.repeats)
+            }
+            _ => return Err("a rule needs either `regex` or `template`".to_string()),
+        };
+        let pattern = Regex::new(&pattern).map_err(|e| e.to_string())?;
+        Ok(Rule {
+            spec,
+            pattern,
+            replacement,
+            repeats,
+        })
+    }
+}
+
+impl Rule {
+    /// Describes the rule for output, e.g. `template "{y}/{*}" -> "{y}"`.
+    pub fn describe(&self) -> String {
+        match (&self.spec.regex, &self.spec.template) {
+            (Some(regex), _) => format!("regex {:?} -> {:?}", regex, self.spec.to),
+            (_, Some(template)) => format!("template {:?} -> {:?}", template, self.spec.to),
+            _ => unreachable!("checked when the rule was compiled"),
+        }
+    }
+
+    /// Rewrites `dir` if the rule matches it.
+    fn apply(&self, dir: &str) -> Option<String> {
+        let captures = self.pattern.captures(dir)?;
+        if self.repeats.iter().any(|(first, again)| {
+            captures.name(first).map(|m| m.as_str()) != captures.name(again).map(|m| m.as_str())
+        }) {
+            return None;
+        }
+        let mut mapped = String::new();
+        captures.expand(&self.replacement, &mut mapped);
+        Some(mapped)
+    }
+}
+
+/// The ordered list of rules.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DirMapping {
+    rules: Vec<Rule>,
+}
+
+/// The result of mapping a directory.
+pub struct Mapped {
+    pub dir: PathBuf,
+    /// The index and the rule that matched, if any.
+    pub rule: Option<(usize, Rule)>,
+}
+
+impl DirMapping {
+    pub fn new(specs: Vec<RuleSpec>) -> Result<DirMapping, String> {
+        let rules = specs
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| Rule::try_from(spec).map_err(|e| format!("rule {}: {}", i + 1, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(DirMapping { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Maps the relative directory of a compressed file to the relative RAW directory.
+    ///
+    /// Directories that are not UTF-8 or match no rule stay unchanged. Fails when a
+    /// rule maps the directory outside the RAW root.
+    pub fn map(&self, dir: &Path) -> Result<Mapped, String> {
+        let unchanged = Mapped {
+            dir: dir.to_path_buf(),
+            rule: None,
+        };
+        let Some(dir_str) = dir.to_str() else {
+            return Ok(unchanged);
+        };
+        for (i, rule) in self.rules.iter().enumerate() {
+            let Some(mapped) = rule.apply(dir_str) else {
+                continue;
+            };
+            let mapped = PathBuf::from(mapped);
+            if !mapped
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(format!(
+                    "mapping rule {} maps {} outside the raw directory: {}",
+                    i + 1,
+                    dir.display(),
+                    mapped.display()
+                ));
+            }
+            return Ok(Mapped {
+                dir: mapped,
+                rule: Some((i + 1, rule.clone())),
+            });
+        }
+        Ok(unchanged)
+    }
+}
+
+/// A template turned into a regex.
+struct Template {
+    /// The anchored regex.
+    pattern: String,
+    /// The names of the capture groups.
+    names: HashSet<String>,
+    /// The pairs of groups that repeated names require to be equal.
+    repeats: Vec<(String, String)>,
+}
+
+fn compile_template(template: &str) -> Result<Template, String> {
+    let mut pattern = String::from("^");
+    let mut names = HashSet::new();
+    let mut repeats = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        pattern.push_str(&regex::escape(&rest[..start]));
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `{{` in template {template:?}"))?;
+        let placeholder = &rest[start + 1..start + end];
+        match placeholder {
+            "*" => pattern.push_str("[^/]*"),
+            "**" => pattern.push_str(".*"),
+            name if is_name(name) => {
+                if names.insert(name.to_string()) {
+                    pattern.push_str(&format!("(?P<{name}>[^/]+?)"));
+                } else {
+                    let again = format!("{name}__{}", repeats.len());
+                    pattern.push_str(&format!("(?P<{again}>[^/]+?)"));
+                    repeats.push((name.to_string(), again));
+                }
+            }
+            _ => return Err(format!("invalid placeholder `{{{placeholder}}}`")),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+    Ok(Template {
+        pattern,
+        names,
+        repeats,
+    })
+}
+
+/// Turns the `to` side of a template rule into a replacement in `regex` syntax.
+fn compile_target(to: &str, names: &HashSet<String>) -> Result<String, String> {
+    let mut replacement = String::new();
+    let mut rest = to;
+    while let Some(start) = rest.find('{') {
+        replacement.push_str(&rest[..start].replace('$', "$$"));
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `{{` in {to:?}"))?;
+        let name = &rest[start + 1..start + end];
+        if !names.contains(name) {
+            return Err(format!("`{{{name}}}` is not captured by the template"));
+        }
+        replacement.push_str(&format!("${{{name}}}"));
+        rest = &rest[start + end + 1..];
+    }
+    replacement.push_str(&rest.replace('$', "$$"));
+    Ok(replacement)
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains("__")
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Prints which RAW directory each sample path of a compressed file maps to.
+pub fn test_samples(mapping: &DirMapping, samples: &[PathBuf]) {
+    for sample in samples {
+        let parent_dir = sample.parent().unwrap_or(Path::new(""));
+        match mapping.map(parent_dir) {
+            Ok(Mapped {
+                dir,
+                rule: Some((i, rule)),
+            }) => println!(
+                "{} -> {} (rule {}: {})",
+                sample.display(),
+                display_dir(&dir),
+                i,
+                rule.describe()
+            ),
+            Ok(Mapped { dir, rule: None }) => println!(
+                "{} -> {} (no rule matches, unchanged)",
+                sample.display(),
+                display_dir(&dir)
+            ),
+            Err(e) => println!("{}: {}", sample.display(), e),
+        }
+    }
+}
+
+fn display_dir(dir: &Path) -> String {
+    if dir.as_os_str().is_empty() {
+        "the raw directory itself".to_string()
+    } else {
+        dir.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(template: &str, to: &str) -> DirMapping {
+        DirMapping::new(vec![RuleSpec {
+            regex: None,
+            template: Some(template.to_string()),
+            to: to.to_string(),
+        }])
+        .unwrap()
+    }
+
+    fn map(mapping: &DirMapping, dir: &str) -> Result<PathBuf, String> {
+        mapping.map(Path::new(dir)).map(|mapped| mapped.dir)
+    }
+
+    #[test]
+    fn compile_template_escapes_literals() {
+        let template = compile_template("{y}/{y}-{m} (1.x)/{*}/{**}").unwrap();
+        assert_eq!(
+            template.pattern,
+            r"^(?P<y>[^/]+?)/(?P<y__0>[^/]+?)\-(?P<m>[^/]+?) \(1\.x\)/[^/]*/.*$"
+        );
+        assert_eq!(
+            template.names,
+            HashSet::from(["y".to_string(), "m".to_string()])
+        );
+        assert_eq!(
+            template.repeats,
+            vec![("y".to_string(), "y__0".to_string())]
+        );
+    }
+
+    #[test]
+    fn compile_template_rejects_bad_placeholders() {
+        assert!(compile_template("{y").is_err());
+        assert!(compile_template("{}").is_err());
+        assert!(compile_template("{1y}").is_err());
+        assert!(compile_template("{a__b}").is_err());
+        assert!(compile_template("{a-b}").is_err());
+    }
+
+    #[test]
+    fn compile_target_escapes_dollars() {
+        let names = HashSet::from(["y".to_string()]);
+        assert_eq!(compile_target("{y}/$1", &names).unwrap(), "${y}/$$1");
+        assert!(compile_target("{m}", &names).is_err());
+        assert!(compile_target("{y", &names).is_err());
+    }
+
+    #[test]
+    fn template_rewrites_the_directory() {
+        let mapping = template("{y}/{y}-{m}-{d} {*}", "{y}/{m}/{d}");
+        assert_eq!(
+            map(&mapping, "2024/2024-05-01 Trip").unwrap(),
+            Path::new("2024/05/01")
+        );
+        // The repeated year has to be the same.
+        assert_eq!(
+            map(&mapping, "2024/2023-05-01 Trip").unwrap(),
+            Path::new("2024/2023-05-01 Trip")
+        );
+        // Patterns match the whole directory.
+        assert_eq!(
+            map(&mapping, "2024/2024-05-01 Trip/x").unwrap(),
+            Path::new("2024/2024-05-01 Trip/x")
+        );
+    }
+
+    #[test]
+    fn regex_rewrites_the_directory() {
+        let mapping = DirMapping::new(vec![RuleSpec {
+            regex: Some(r"(\d{4})/(\d{4})-(\d{2}).*".to_string()),
+            template: None,
+            to: "$1/$3".to_string(),
+        }])
+        .unwrap();
+        assert_eq!(
+            map(&mapping, "2024/2024-05 Trip").unwrap(),
+            Path::new("2024/05")
+        );
+        assert_eq!(map(&mapping, "misc").unwrap(), Path::new("misc"));
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let mapping = DirMapping::new(vec![
+            RuleSpec {
+                regex: None,
+                template: Some("{a}/{**}".to_string()),
+                to: "{a}".to_string(),
+            },
+            RuleSpec {
+                regex: None,
+                template: Some("{**}".to_string()),
+                to: "all".to_string(),
+            },
+        ])
+        .unwrap();
+        let mapped = mapping.map(Path::new("x/y")).unwrap();
+        assert_eq!(mapped.dir, Path::new("x"));
+        assert_eq!(mapped.rule.map(|(i, _)| i), Some(1));
+        assert_eq!(map(&mapping, "x").unwrap(), Path::new("all"));
+    }
+
+    #[test]
+    fn rules_need_one_pattern() {
+        let spec = |regex: Option<&str>, template: Option<&str>| RuleSpec {
+            regex: regex.map(str::to_string),
+            template: template.map(str::to_string),
+            to: String::new(),
+        };
+        assert!(DirMapping::new(vec![spec(None, None)]).is_err());
+        assert!(DirMapping::new(vec![spec(Some("a"), Some("a"))]).is_err());
+        assert!(DirMapping::new(vec![spec(Some("("), None)]).is_err());
+    }
+
+    #[test]
+    fn mapping_outside_the_raw_root_fails() {
+        let mapping = template("{a}/{b}", "{a}/../../{b}");
+        assert!(map(&mapping, "x/y").is_err());
+        let mapping = template("{a}", "/{a}");
+        assert!(map(&mapping, "x").is_err());
+        // A captured `..` is caught as well.
+        let mapping = template("{a}/{*}", "{a}");
+        assert!(map(&mapping, "../x").is_err());
+        assert_eq!(map(&mapping, "./x").unwrap(), Path::new("."));
+    }
+}
@@ -0,0 +1,724 @@
+//! Finding the RAW file that belongs to a compressed image.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    capture::CaptureId,
+    formats::{CompressedFormats, RawFormats},
+    layout::{Layout, LayoutKind},
+    mapping::DirMapping,
+    stems::{Normalization, StemRules},
+    trash,
+};
+
+/// How a compressed file is paired with its RAW.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PairBy {
+    /// By file stem.
+    #[default]
+    Stem,
+    /// By EXIF capture time, body serial and image id, ignoring names.
+    Exif,
+    /// By file stem, and by EXIF for files whose stem has no RAW.
+    StemOrExif,
+}
+
+/// The settings that decide which files are compared and which RAW file
+/// matches a compressed file.
+///
+/// Plans store the matcher they were created with, so `apply` re-checks
+/// entries under the same rules.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Matcher {
+    pub raw_formats: RawFormats,
+    pub compressed_formats: CompressedFormats,
+    /// Also match RAWs with the same stem in any other directory of the RAW tree.
+    pub match_anywhere: bool,
+    /// Rewrites the relative directory of a compressed file before the lookup.
+    pub mapping: DirMapping,
+    pub pair_by: PairBy,
+    /// Suffixes stripped from compressed stems when the unchanged stem has no RAW.
+    pub stems: StemRules,
+    /// The Unicode form stems are compared under, if any.
+    pub normalize_stems: Option<Normalization>,
+    /// How RAW and compressed files are arranged.
+    pub layout: Layout,
+}
+
+/// The result of looking for the RAW file of a compressed image.
+pub enum RawMatch {
+    Matched {
+        raw: PathBuf,
+        /// How the RAW was found, when not by its stem in the mirrored directory.
+        via: Option<String>,
+    },
+    NotMatched,
+    /// With `match_anywhere`, RAWs with the stem exist in several directories
+    /// other than the mirrored one, or several RAWs have the same capture
+    /// metadata, so it is unclear which one belongs to the file.
+    Ambiguous(Vec<PathBuf>),
+    /// The lookup failed, e.g. on a permission error, a stale NFS handle or an
+    /// unmounted volume. Such files are never deleted.
+    Unknown(io::Error),
+}
+
+/// The RAW files of a tree, keyed by relative directory and stem.
+///
+/// Walking the RAW root once is much cheaper than probing it for every
+/// compressed file, especially on network shares.
+pub struct RawIndex {
+    matcher: Matcher,
+    root: PathBuf,
+    files: HashMap<(PathBuf, OsString), Vec<PathBuf>>,
+    /// The relative directories of the tree, for picking sibling RAW folders.
+    dirs: HashSet<PathBuf>,
+    /// The same files keyed by stem alone, with `match_anywhere`.
+    by_stem: Option<HashMap<OsString, Vec<PathBuf>>>,
+    /// Paths the walk could not read, relative to the root and with the error,
+    /// so lookups that depend on them are undetermined instead of unmatched.
+    unreadable: Vec<(PathBuf, io::ErrorKind, String)>,
+    /// The capture metadata of the RAWs, read per relative directory on first
+    /// use, or for the whole tree under `None` with `match_anywhere`.
+    captures: RefCell<HashMap<Option<PathBuf>, Rc<Captures>>>,
+}
+
+/// What the compressed files say about the RAWs of an index, for deleting
+/// RAWs in the opposite direction.
+#[derive(Default)]
+pub struct Claims {
+    /// RAWs that are the match of a compressed file, with that file.
+    matched: HashMap<PathBuf, PathBuf>,
+    /// RAWs that are one of several candidates of a compressed file.
+    candidates: HashSet<PathBuf>,
+    /// Directories below which any RAW may belong to a compressed file that
+    /// could not be checked, `None` for the whole tree, with the reason.
+    blocked: Vec<(Option<PathBuf>, String)>,
+}
+
+/// Whether a RAW file belongs to a compressed file.
+pub enum RawStatus {
+    /// The RAW is the match of this compressed file.
+    Matched(PathBuf),
+    /// The RAW is one of several candidates of a compressed file.
+    Ambiguous,
+    /// A compressed file that could not be checked may belong to the RAW.
+    Undetermined(String),
+    Orphaned,
+}
+
+impl Claims {
+    pub fn status(&self, raw: &Path) -> RawStatus {
+        if let Some(compressed_file) = self.matched.get(raw) {
+            return RawStatus::Matched(compressed_file.clone());
+        }
+        if self.candidates.contains(raw) {
+            return RawStatus::Ambiguous;
+        }
+        match self
+            .blocked
+            .iter()
+            .find(|(dir, _)| dir.as_ref().is_none_or(|dir| raw.starts_with(dir)))
+        {
+            Some((_, reason)) => RawStatus::Undetermined(reason.clone()),
+            None => RawStatus::Orphaned,
+        }
+    }
+}
+
+/// The capture metadata of a set of RAWs.
+#[derive(Default)]
+struct Captures {
+    by_datetime: HashMap<String, Vec<(PathBuf, CaptureId)>>,
+    /// RAWs whose metadata could not be read, any of which might be the match.
+    errors: Vec<(PathBuf, io::ErrorKind, String)>,
+}
+
+impl Matcher {
+    /// Returns whether lookups may find several candidate RAWs or RAWs under
+    /// other names, which the direct lookup of [`Matcher::find_matching_raw`]
+    /// cannot answer.
+    pub fn can_be_ambiguous(&self) -> bool {
+        self.match_anywhere || self.pair_by != PairBy::Stem
+    }
+
+    /// Walks `raw_root` into an index, returning the paths that could not be read as well.
+    ///
+    /// Symlinks are followed, as a lookup of the RAW path would.
+    pub fn index(&self, raw_root: &Path) -> (RawIndex, Vec<walkdir::Error>) {
+        let mut files: HashMap<(PathBuf, OsString), Vec<PathBuf>> = HashMap::new();
+        let mut dirs = HashSet::new();
+        let mut unreadable = Vec::new();
+        let mut walk_errors = Vec::new();
+
+        for entry in trash::skip_trash(WalkDir::new(raw_root).follow_links(true)) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // An error without a path below the root makes every lookup undetermined.
+                    let relative = e
+                        .path()
+                        .and_then(|path| path.strip_prefix(raw_root).ok())
+                        .unwrap_or(Path::new(""));
+                    let kind = e.io_error().map_or(io::ErrorKind::Other, |e| e.kind());
+                    unreadable.push((relative.to_path_buf(), kind, e.to_string()));
+                    walk_errors.push(e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if entry.file_type().is_dir()
+                && let Ok(relative_dir) = path.strip_prefix(raw_root)
+            {
+                dirs.insert(relative_dir.to_path_buf());
+            }
+            if !entry.file_type().is_file() || !self.raw_formats.is_raw(path) {
+                continue;
+            }
+            let (Ok(relative_path), Some(stem)) = (path.strip_prefix(raw_root), path.file_stem())
+            else {
+                continue;
+            };
+            if !self.layout.includes_raw(relative_path) {
+                continue;
+            }
+            let parent_dir = relative_path.parent().unwrap_or(Path::new(""));
+            files
+                .entry((
+                    parent_dir.to_path_buf(),
+                    Normalization::key(self.normalize_stems, stem),
+                ))
+                .or_default()
+                .push(path.to_path_buf());
+        }
+        for raws in files.values_mut() {
+            raws.sort();
+        }
+
+        let by_stem = self.match_anywhere.then(|| {
+            let mut by_stem: HashMap<OsString, Vec<PathBuf>> = HashMap::new();
+            for ((_, stem), raws) in &files {
+                by_stem
+                    .entry(stem.clone())
+                    .or_default()
+                    .extend(raws.iter().cloned());
+            }
+            for raws in by_stem.values_mut() {
+                raws.sort();
+            }
+            by_stem
+        });
+
+        (
+            RawIndex {
+                matcher: self.clone(),
+                root: raw_root.to_path_buf(),
+                files,
+                dirs,
+                by_stem,
+                unreadable,
+                captures: RefCell::new(HashMap::new()),
+            },
+            walk_errors,
+        )
+    }
+
+    /// Returns the relative RAW directory to look in for `compressed_file`,
+    /// after the mapping rules and the layout, and the stem to look for.
+    ///
+    /// `exists` tells which relative RAW directories exist, to pick between
+    /// the sibling RAW folders of a shoot.
+    fn lookup_key<'a>(
+        &self,
+        compressed_file: &'a Path,
+        compressed_root: &Path,
+        exists: impl Fn(&Path) -> bool,
+    ) -> io::Result<(PathBuf, &'a OsStr)> {
+        let (Ok(relative_path), Some(file_stem)) = (
+            compressed_file.strip_prefix(compressed_root),
+            compressed_file.file_stem(),
+        ) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a file below {}", compressed_root.display()),
+            ));
+        };
+        let parent_dir = relative_path.parent().unwrap_or(Path::new(""));
+        let mapped = self
+            .mapping
+            .map(parent_dir)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let raw_dir = self
+            .layout
+            .raw_dir(&mapped.dir, exists)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok((raw_dir, file_stem))
+    }
+
+    /// Looks for a RAW file with the stem of `compressed_file` in the mirrored
+    /// directory below `raw_root`, or the one the mapping rules point to.
+    ///
+    /// This reads the directory right away and only compares stems, for
+    /// re-checks of single files; scans use [`RawIndex::find_matching_raw`].
+    ///
+    /// Extensions are compared case-insensitively, so `.raf`, `.RAF` and `.Raf`
+    /// all match. If several RAW files share the stem, the first by name wins.
+    /// The stem rules are tried after the unchanged stem. With
+    /// `normalize_stems`, both stems are compared in that Unicode form.
+    pub fn find_matching_raw(
+        &self,
+        compressed_file: &Path,
+        compressed_root: &Path,
+        raw_root: &Path,
+    ) -> RawMatch {
+        let exists = |dir: &Path| raw_root.join(dir).is_dir();
+        let (parent_dir, file_stem) =
+            match self.lookup_key(compressed_file, compressed_root, exists) {
+                Ok(key) => key,
+                Err(e) => return RawMatch::Unknown(e),
+            };
+
+        let raw_dir = raw_root.join(&parent_dir);
+
+        let entries = match fs::read_dir(&raw_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return RawMatch::NotMatched,
+            Err(e) => return RawMatch::Unknown(e),
+        };
+
+        let variants = self.stems.variants(file_stem, self.normalize_stems);
+        let mut candidates = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return RawMatch::Unknown(e),
+            };
+            let path = entry.path();
+            let Some(variant) = path.file_stem().and_then(|stem| {
+                let stem = Normalization::key(self.normalize_stems, stem);
+                variants.iter().position(|variant| variant.stem == stem)
+            }) else {
+                continue;
+            };
+            if !self.raw_formats.is_raw(&path) {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {}
+                Ok(_) => candidates.push((variant, path)),
+                Err(e) => return RawMatch::Unknown(e),
+            }
+        }
+
+        // Earlier variants win, so the unchanged stem is preferred.
+        match candidates.into_iter().min() {
+            Some((variant, raw)) => RawMatch::Matched {
+                raw,
+                via: variants[variant].describe(),
+            },
+            None => RawMatch::NotMatched,
+        }
+    }
+}
+
+impl RawIndex {
+    /// Looks up the RAW file for `compressed_file` with the rules of [`Matcher::find_matching_raw`].
+    ///
+    /// With `match_anywhere`, a file without a RAW in the mirrored directory
+    /// matches a RAW with its stem anywhere in the tree, as long as all such
+    /// RAWs are in a single directory. Depending on `pair_by`, RAWs are found
+    /// by stem, by EXIF, or by EXIF when the stem finds nothing.
+    pub fn find_matching_raw(&self, compressed_file: &Path, compressed_root: &Path) -> RawMatch {
+        let (parent_dir, file_stem) =
+            match self
+                .matcher
+                .lookup_key(compressed_file, compressed_root, |dir| {
+                    self.dirs.contains(dir)
+                }) {
+                Ok(key) => key,
+                Err(e) => return RawMatch::Unknown(e),
+            };
+
+        let by_stem = match self.matcher.pair_by {
+            PairBy::Exif => None,
+            PairBy::Stem | PairBy::StemOrExif => Some(self.match_stem(&parent_dir, file_stem)),
+        };
+        match (self.matcher.pair_by, by_stem) {
+            (PairBy::Stem, Some(raw_match)) => raw_match,
+            (PairBy::StemOrExif, Some(raw_match)) if !matches!(raw_match, RawMatch::NotMatched) => {
+                raw_match
+            }
+            _ => self.match_exif(compressed_file, &parent_dir),
+        }
+    }
+
+    /// Returns whether `raw` is in the directory the lookup for
+    /// `compressed_file` looks in first, the mirrored one after the mapping
+    /// rules and the layout, so it was not found anywhere else.
+    pub fn is_mirrored(&self, compressed_file: &Path, compressed_root: &Path, raw: &Path) -> bool {
+        self.matcher
+            .lookup_key(compressed_file, compressed_root, |dir| {
+                self.dirs.contains(dir)
+            })
+            .is_ok_and(|(dir, _)| raw.parent() == Some(self.root.join(dir).as_path()))
+    }
+
+    /// Returns all RAW files of the index, sorted.
+    pub fn raws(&self) -> Vec<&PathBuf> {
+        let mut raws: Vec<&PathBuf> = self.files.values().flatten().collect();
+        raws.sort();
+        raws
+    }
+
+    /// Looks up the RAW file for `compressed_file` and records in `claims`
+    /// which RAWs it belongs to or might belong to.
+    pub fn claim(
+        &self,
+        claims: &mut Claims,
+        compressed_file: &Path,
+        compressed_root: &Path,
+    ) -> RawMatch {
+        let raw_match = self.find_matching_raw(compressed_file, compressed_root);
+        match &raw_match {
+            RawMatch::Matched { raw, .. } => {
+                claims
+                    .matched
+                    .entry(raw.clone())
+                    .or_insert_with(|| compressed_file.to_path_buf());
+            }
+            RawMatch::Ambiguous(raws) => claims.candidates.extend(raws.iter().cloned()),
+            RawMatch::NotMatched => {}
+            RawMatch::Unknown(e) => {
+                let exists = |dir: &Path| self.dirs.contains(dir);
+                let scope = match self
+                    .matcher
+                    .lookup_key(compressed_file, compressed_root, exists)
+                {
+                    Ok((dir, _)) if !self.matcher.match_anywhere => Some(self.root.join(dir)),
+                    // A shoot without RAW folders only blocks its own RAWs.
+                    Err(_)
+                        if self.matcher.layout.kind == LayoutKind::Sibling
+                            && !self.matcher.match_anywhere
+                            && self.matcher.mapping.is_empty() =>
+                    {
+                        compressed_file
+                            .strip_prefix(compressed_root)
+                            .ok()
+                            .and_then(|relative| self.matcher.layout.shoot(relative))
+                            .map(|shoot| self.root.join(shoot))
+                    }
+                    _ => None,
+                };
+                claims.blocked.push((
+                    scope,
+                    format!("cannot check {}: {}", compressed_file.display(), e),
+                ));
+            }
+        }
+        raw_match
+    }
+
+    /// Records in `claims` that `relative`, a path of the compressed tree,
+    /// could not be read, so the RAWs its files would match are undetermined.
+    pub fn claim_unreadable(&self, claims: &mut Claims, relative: &Path, reason: String) {
+        // Mapping rules may map a directory and its subdirectories anywhere,
+        // so only plain mirrored lookups narrow the affected RAWs down. In the
+        // sibling layout, the files below a compressed folder pair with the
+        // RAW folders of its shoot.
+        let scope = match relative.parent() {
+            _ if self.matcher.layout.kind == LayoutKind::Sibling => {
+                match self.matcher.layout.shoot(relative) {
+                    Some(shoot)
+                        if !self.matcher.match_anywhere && self.matcher.mapping.is_empty() =>
+                    {
+                        Some(self.root.join(shoot))
+                    }
+                    _ => None,
+                }
+            }
+            Some(parent)
+                if !relative.as_os_str().is_empty()
+                    && !self.matcher.match_anywhere
+                    && self.matcher.mapping.is_empty() =>
+            {
+                Some(self.root.join(parent))
+            }
+            _ => None,
+        };
+        claims.blocked.push((scope, reason));
+    }
+
+    fn match_stem(&self, parent_dir: &Path, file_stem: &OsStr) -> RawMatch {
+        let variants = self
+            .matcher
+            .stems
+            .variants(file_stem, self.matcher.normalize_stems);
+
+        for variant in &variants {
+            let key = (parent_dir.to_path_buf(), variant.stem.clone());
+            if let Some(raw) = self.files.get(&key).and_then(|raws| raws.first()) {
+                return RawMatch::Matched {
+                    raw: raw.clone(),
+                    via: variant.describe(),
+                };
+            }
+        }
+
+        if let Some(by_stem) = &self.by_stem {
+            for variant in &variants {
+                let Some(raws) = by_stem.get(&variant.stem) else {
+                    continue;
+                };
+                let first_dir = raws[0].parent();
+                return if raws.iter().all(|raw| raw.parent() == first_dir) {
+                    let via = match variant.describe() {
+                        Some(rules) => format!("{rules}, found in another directory"),
+                        None => "stem found in another directory".to_string(),
+                    };
+                    RawMatch::Matched {
+                        raw: raws[0].clone(),
+                        via: Some(via),
+                    }
+                } else {
+                    RawMatch::Ambiguous(raws.clone())
+                };
+            }
+        }
+
+        match self.unreadable_for(parent_dir) {
+            Some(e) => RawMatch::Unknown(e),
+            None => RawMatch::NotMatched,
+        }
+    }
+
+    fn match_exif(&self, compressed_file: &Path, parent_dir: &Path) -> RawMatch {
+        let capture = match CaptureId::read(compressed_file) {
+            Ok(Some(capture)) => capture,
+            // The stem check already found nothing, so the file stays unmatched.
+            Ok(None) if self.matcher.pair_by == PairBy::StemOrExif => return RawMatch::NotMatched,
+            Ok(None) => {
+                return RawMatch::Unknown(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no EXIF capture time to pair by",
+                ));
+            }
+            Err(e) => return RawMatch::Unknown(e),
+        };
+
+        let scope = (!self.matcher.match_anywhere).then(|| parent_dir.to_path_buf());
+        let captures = self.captures(scope);
+        let raws: Vec<&PathBuf> = captures
+            .by_datetime
+            .get(capture.datetime())
+            .into_iter()
+            .flatten()
+            .filter(|(_, raw_capture)| raw_capture.matches(&capture))
+            .map(|(raw, _)| raw)
+            .collect();
+
+        match raws.as_slice() {
+            [raw] => RawMatch::Matched {
+                raw: raw.to_path_buf(),
+                via: Some(format!("EXIF {}", capture.describe())),
+            },
+            [] => {
+                if let Some((_, kind, message)) = captures.errors.first() {
+                    return RawMatch::Unknown(io::Error::new(*kind, message.clone()));
+                }
+                match self.unreadable_for(parent_dir) {
+                    Some(e) => RawMatch::Unknown(e),
+                    None => RawMatch::NotMatched,
+                }
+            }
+            raws => RawMatch::Ambiguous(raws.iter().map(|raw| raw.to_path_buf()).collect()),
+        }
+    }
+
+    /// Returns the capture metadata of the RAWs in `dir`, or of all RAWs for `None`.
+    fn captures(&self, dir: Option<PathBuf>) -> Rc<Captures> {
+        if let Some(captures) = self.captures.borrow().get(&dir) {
+            return captures.clone();
+        }
+
+        let mut captures = Captures::default();
+        let raws = self
+            .files
+            .iter()
+            .filter(|((raw_dir, _), _)| dir.as_ref().is_none_or(|dir| dir == raw_dir))
+            .flat_map(|(_, raws)| raws);
+        for raw in raws {
+            match CaptureId::read(raw) {
+                Ok(Some(capture)) => captures
+                    .by_datetime
+                    .entry(capture.datetime().to_string())
+                    .or_default()
+                    .push((raw.clone(), capture)),
+                // A RAW without readable metadata, e.g. a `.cr3`, could be
+                // the match of any file, so those files stay undetermined.
+                Ok(None) => captures.errors.push((
+                    raw.clone(),
+                    io::ErrorKind::InvalidData,
+                    format!("no readable EXIF capture time in {}", raw.display()),
+                )),
+                Err(e) => captures.errors.push((
+                    raw.clone(),
+                    e.kind(),
+                    format!("cannot read EXIF of {}: {}", raw.display(), e),
+                )),
+            }
+        }
+        for raws in captures.by_datetime.values_mut() {
+            raws.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+
+        let captures = Rc::new(captures);
+        self.captures.borrow_mut().insert(dir, captures.clone());
+        captures
+    }
+
+    /// Returns the error of a path the lookup in `parent_dir` depends on, if
+    /// it could not be read: with `match_anywhere` any path, otherwise the
+    /// directory, one of its parents, or an entry in it.
+    fn unreadable_for(&self, parent_dir: &Path) -> Option<io::Error> {
+        self.unreadable
+            .iter()
+            .find(|(path, _, _)| {
+                self.matcher.match_anywhere
+                    || parent_dir.starts_with(path)
+                    || path.parent() == Some(parent_dir)
+            })
+            .map(|(_, kind, message)| io::Error::new(*kind, message.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn touch(root: &Path, files: &[&str]) {
+        for file in files {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), "").unwrap();
+        }
+    }
+
+    fn matched(raw_match: RawMatch) -> Option<PathBuf> {
+        match raw_match {
+            RawMatch::Matched { raw, .. } => Some(raw),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn lookups_match_mirrored_stems_in_any_case() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(&raw_root, &["a/A.raf", "b/B.RAF"]);
+        touch(&compressed_root, &["a/A.JPG", "a/B.jpg", "c/C.jpg"]);
+        let matcher = Matcher::default();
+        let (index, walk_errors) = matcher.index(&raw_root);
+        assert!(walk_errors.is_empty());
+
+        let a = compressed_root.join("a/A.JPG");
+        assert_eq!(
+            matched(index.find_matching_raw(&a, &compressed_root)),
+            Some(raw_root.join("a/A.raf"))
+        );
+        assert_eq!(
+            matched(matcher.find_matching_raw(&a, &compressed_root, &raw_root)),
+            Some(raw_root.join("a/A.raf"))
+        );
+        // A RAW with the stem in another directory only matches with match_anywhere.
+        for file in ["a/B.jpg", "c/C.jpg"] {
+            let file = compressed_root.join(file);
+            assert!(matches!(
+                index.find_matching_raw(&file, &compressed_root),
+                RawMatch::NotMatched
+            ));
+            assert!(matches!(
+                matcher.find_matching_raw(&file, &compressed_root, &raw_root),
+                RawMatch::NotMatched
+            ));
+        }
+    }
+
+    #[test]
+    fn unreadable_raw_paths_make_lookups_unknown() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(&raw_root, &["a/A.RAF", "d"]);
+        touch(
+            &compressed_root,
+            &["a/A.jpg", "b/B.jpg", "c/C.jpg", "d/D.jpg"],
+        );
+        // Like a RAW on a volume that is not mounted.
+        fs::create_dir(raw_root.join("b")).unwrap();
+        symlink(tmp.path().join("missing"), raw_root.join("b/B.RAF")).unwrap();
+        let matcher = Matcher::default();
+        let (index, walk_errors) = matcher.index(&raw_root);
+        assert_eq!(walk_errors.len(), 1);
+
+        let lookup =
+            |file: &str| index.find_matching_raw(&compressed_root.join(file), &compressed_root);
+        assert!(matches!(lookup("a/A.jpg"), RawMatch::Matched { .. }));
+        assert!(matches!(lookup("b/B.jpg"), RawMatch::Unknown(_)));
+        // Other directories do not depend on the unreadable path.
+        assert!(matches!(lookup("c/C.jpg"), RawMatch::NotMatched));
+
+        // The direct lookup reads the directory, which is a file here.
+        let file = compressed_root.join("d/D.jpg");
+        assert!(matches!(
+            matcher.find_matching_raw(&file, &compressed_root, &raw_root),
+            RawMatch::Unknown(_)
+        ));
+    }
+
+    #[test]
+    fn match_anywhere_is_ambiguous_across_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(
+            &raw_root,
+            &["x/A.RAF", "y/B.RAF", "z/B.NEF", "a/C.RAF", "w/C.RAF"],
+        );
+        touch(&compressed_root, &["a/A.jpg", "a/B.jpg", "a/C.jpg"]);
+        let matcher = Matcher {
+            match_anywhere: true,
+            ..Matcher::default()
+        };
+        assert!(matcher.can_be_ambiguous());
+        let (index, _) = matcher.index(&raw_root);
+        let lookup =
+            |file: &str| index.find_matching_raw(&compressed_root.join(file), &compressed_root);
+
+        match lookup("a/A.jpg") {
+            RawMatch::Matched { raw, via } => {
+                assert_eq!(raw, raw_root.join("x/A.RAF"));
+                assert_eq!(via.as_deref(), Some("stem found in another directory"));
+            }
+            _ => panic!("a/A.jpg should match x/A.RAF"),
+        }
+        match lookup("a/B.jpg") {
+            RawMatch::Ambiguous(raws) => {
+                assert_eq!(raws, [raw_root.join("y/B.RAF"), raw_root.join("z/B.NEF")])
+            }
+            _ => panic!("a/B.jpg should be ambiguous"),
+        }
+        // The mirrored directory wins over other candidates.
+        assert_eq!(matched(lookup("a/C.jpg")), Some(raw_root.join("a/C.RAF")));
+    }
+}
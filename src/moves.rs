@@ -0,0 +1,281 @@
+//! Moving images together with their sidecars, for `relayout` and `reconcile`.
+//!
+//! All moves of a run are planned before the first file is touched, so a
+//! destination that is taken keeps the whole group in place. Every move is
+//! recorded in a manifest as it happens, so an interrupted run can be traced.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use chrono::Local;
+use serde::Serialize;
+
+use crate::fsutil;
+
+pub struct MoveOptions {
+    pub dry_run: bool,
+    pub verbose: bool,
+    /// Where to write the manifest, by default a dated file in the working directory.
+    pub manifest: Option<PathBuf>,
+}
+
+/// Every line of a manifest after the header, one per moved file.
+#[derive(Serialize)]
+struct Entry {
+    #[serde(with = "fsutil::lossless")]
+    from: PathBuf,
+    #[serde(with = "fsutil::lossless")]
+    to: PathBuf,
+}
+
+/// An image and its sidecars, which are moved together, the image first.
+pub struct Group {
+    pub raw: bool,
+    moves: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Group {
+    /// Plans moving `image` into `target_dir`, with its sidecars keeping their
+    /// path relative to the directory of the image.
+    pub fn new(image: PathBuf, raw: bool, sidecars: Vec<PathBuf>, target_dir: &Path) -> Group {
+        let image_dir = image.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut moves = vec![(
+            image.clone(),
+            target_dir.join(image.file_name().unwrap_or_default()),
+        )];
+        for sidecar in sidecars {
+            let below = sidecar.strip_prefix(&image_dir).unwrap_or(&sidecar);
+            let destination = target_dir.join(below);
+            moves.push((sidecar, destination));
+        }
+        Group { raw, moves }
+    }
+
+    pub fn image(&self) -> &Path {
+        &self.moves[0].0
+    }
+
+    pub fn destination(&self) -> &Path {
+        &self.moves[0].1
+    }
+}
+
+/// The files a run moved, or would move in dry-run mode.
+#[derive(Default)]
+pub struct Moved {
+    /// How many RAW and compressed images were moved.
+    pub raw: usize,
+    pub compressed: usize,
+    pub sidecars: usize,
+    pub errors: usize,
+}
+
+impl Moved {
+    fn count(&mut self, group: &Group, i: usize) {
+        match i {
+            0 if group.raw => self.raw += 1,
+            0 => self.compressed += 1,
+            _ => self.sidecars += 1,
+        }
+    }
+}
+
+/// The groups of a run that can be moved, and those that stay in place.
+#[derive(Default)]
+pub struct MovePlan {
+    groups: Vec<Group>,
+    destinations: HashSet<PathBuf>,
+    /// How many images are where they belong already.
+    pub in_place: usize,
+    /// Images that stay in place with their sidecars, with the destination
+    /// that was in the way and why.
+    pub conflicts: Vec<(PathBuf, PathBuf, String)>,
+}
+
+impl MovePlan {
+    /// Adds `group`, unless it is in place already or one of its destinations
+    /// exists or is taken by an earlier group.
+    pub fn add(&mut self, group: Group) {
+        if group.moves.iter().all(|(from, to)| from == to) {
+            self.in_place += 1;
+            return;
+        }
+        let taken = group.moves.iter().find_map(|(from, to)| {
+            if self.destinations.contains(to) {
+                Some((to.clone(), "taken by another file"))
+            } else if from != to && to.symlink_metadata().is_ok() {
+                Some((to.clone(), "already exists"))
+            } else {
+                None
+            }
+        });
+        if let Some((destination, reason)) = taken {
+            self.conflicts
+                .push((group.image().to_path_buf(), destination, reason.to_string()));
+            return;
+        }
+        self.destinations
+            .extend(group.moves.iter().map(|(_, to)| to.clone()));
+        self.groups.push(group);
+    }
+
+    /// Moves the planned files, or lists them in dry-run mode, and returns
+    /// what was moved.
+    ///
+    /// The manifest starts with `header`. Directories the moves leave empty
+    /// are removed up to `roots`.
+    pub fn execute(
+        &self,
+        header: &impl Serialize,
+        command: &str,
+        roots: &[&Path],
+        options: &MoveOptions,
+    ) -> Moved {
+        let mut moved = Moved::default();
+        if options.dry_run {
+            println!("\nDry run mode - files that would be moved:");
+            for group in &self.groups {
+                for (i, (from, to)) in group.moves.iter().enumerate() {
+                    let indent = if i == 0 { "  " } else { "    + " };
+                    println!("{}{} -> {}", indent, from.display(), to.display());
+                    moved.count(group, i);
+                }
+            }
+            return moved;
+        }
+        if self.groups.is_empty() {
+            return moved;
+        }
+
+        let manifest_path = options.manifest.clone().unwrap_or_else(|| {
+            PathBuf::from(format!(
+                "{}-{}.jsonl",
+                command,
+                Local::now().format("%Y-%m-%d_%H%M%S")
+            ))
+        });
+        let mut manifest = match open_manifest(&manifest_path, header) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                eprintln!(
+                    "Error: Cannot create manifest {}: {}",
+                    manifest_path.display(),
+                    e
+                );
+                process::exit(1);
+            }
+        };
+        println!(
+            "\nMoving {} files, recording them in {}...",
+            self.destinations.len(),
+            manifest_path.display()
+        );
+
+        let mut emptied = BTreeSet::new();
+        for group in &self.groups {
+            for (i, (from, to)) in group.moves.iter().enumerate() {
+                // The entry is prepared first, so a file is never moved
+                // without a way to record it.
+                if let Err(e) = manifest_line(from, to)
+                    .and_then(|line| move_recorded(&mut manifest, from, to, line, &mut moved))
+                {
+                    eprintln!("  Error moving {}: {}", from.display(), e);
+                    moved.errors += 1;
+                    // Without its image, a sidecar stays where it is.
+                    if i == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                moved.count(group, i);
+                if options.verbose {
+                    println!("  Moved: {} -> {}", from.display(), to.display());
+                }
+                if let Some(parent) = from.parent() {
+                    emptied.insert(parent.to_path_buf());
+                }
+            }
+        }
+        remove_empty_dirs(emptied, roots);
+        moved
+    }
+
+    /// Lists the images that stay in place because of a conflict.
+    pub fn print_conflicts(&self) {
+        if self.conflicts.is_empty() {
+            return;
+        }
+        println!("\nThese files and their sidecars were left in place:");
+        for (image, destination, reason) in &self.conflicts {
+            println!(
+                "  {} -> {} ({})",
+                image.display(),
+                destination.display(),
+                reason
+            );
+        }
+    }
+}
+
+fn open_manifest(path: &Path, header: &impl Serialize) -> io::Result<File> {
+    let mut manifest = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(path)?;
+    writeln!(manifest, "{}", serde_json::to_string(header)?)?;
+    Ok(manifest)
+}
+
+/// Returns the manifest line for moving `from` to `to`.
+fn manifest_line(from: &Path, to: &Path) -> io::Result<String> {
+    let entry = Entry {
+        from: std::path::absolute(from)?,
+        to: std::path::absolute(to)?,
+    };
+    Ok(serde_json::to_string(&entry)?)
+}
+
+/// Moves `from` to `to` and records `line` right away.
+///
+/// A move that cannot be recorded still counts as done, since the file is at
+/// `to` by then and its sidecars have to follow it; the error is counted in
+/// `moved`.
+fn move_recorded(
+    manifest: &mut File,
+    from: &Path,
+    to: &Path,
+    line: String,
+    moved: &mut Moved,
+) -> io::Result<()> {
+    fsutil::move_file(from, to)?;
+    if let Err(e) = writeln!(manifest, "{line}").and_then(|()| manifest.flush()) {
+        eprintln!(
+            "  Error recording the move of {} in the manifest: {}",
+            from.display(),
+            e
+        );
+        moved.errors += 1;
+    }
+    Ok(())
+}
+
+/// Removes the directories in `dirs` and their parents that the moves left
+/// empty, stopping at the roots.
+fn remove_empty_dirs(dirs: BTreeSet<PathBuf>, roots: &[&Path]) {
+    // Deeper directories sort after their parents, so they go first.
+    for dir in dirs.into_iter().rev() {
+        for ancestor in dir.ancestors() {
+            if roots.contains(&ancestor) || !roots.iter().any(|root| ancestor.starts_with(root)) {
+                break;
+            }
+            if fs::remove_dir(ancestor).is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Two-phase cleaning: `plan` records what would be deleted, `apply` deletes it.
+//!
+//! A plan stores every selected file, compressed or RAW depending on the mode,
+//! and its sidecars with their size, modification time and inode, plus the RAW
+//! file that justified deleting a matched image. `apply` re-checks each entry
+//! and sidecar right before touching it and skips anything that changed since
+//! the plan was written, so a reviewed plan cannot delete more than was reviewed.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    DeleteMode,
+    delete::{self, DeleteBackend, DeleteOptions},
+    fsutil::{self, is_within},
+    matching::{Claims, Matcher, RawIndex, RawMatch, RawStatus},
+    protect::Protection,
+    select::{Counts, Labels, Selection, claim_raws, get_compressed_files},
+    sidecars::{Registry, Sidecars},
+};
+
+#[derive(Serialize, Deserialize)]
+struct Plan {
+    mode: DeleteMode,
+    #[serde(with = "fsutil::lossless")]
+    raw_root: PathBuf,
+    #[serde(with = "fsutil::lossless")]
+    compressed_root: PathBuf,
+    /// The matching rules, so entries are re-checked the way they were selected.
+    #[serde(default)]
+    matcher: Matcher,
+    created: String,
+    /// The files scanned by RAW status, for the deletion limits.
+    #[serde(flatten)]
+    counts: Counts,
+    /// Whether matched entries may have several candidate RAWs.
+    #[serde(default)]
+    allow_ambiguous: bool,
+    entries: Vec<PlanEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PlanEntry {
+    #[serde(flatten)]
+    file: FileState,
+    /// The RAW file that matched, recorded for matched deletions.
+    #[serde(default, with = "fsutil::lossless::option")]
+    raw: Option<PathBuf>,
+    /// The sidecars that go with the file. Sidecars that are not recorded are
+    /// left in place.
+    #[serde(default)]
+    sidecars: Vec<FileState>,
+}
+
+/// A file as it was when the plan was written.
+#[derive(Serialize, Deserialize)]
+struct FileState {
+    #[serde(with = "fsutil::lossless")]
+    path: PathBuf,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    inode: u64,
+}
+
+impl FileState {
+    fn read(path: &Path) -> io::Result<FileState> {
+        let meta = fs::symlink_metadata(path)?;
+        Ok(FileState {
+            path: path.to_path_buf(),
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            inode: meta.ino(),
+        })
+    }
+
+    /// Verifies that the file is still the one that was planned.
+    fn check(&self) -> Result<(), String> {
+        let meta = fs::symlink_metadata(&self.path).map_err(|e| format!("cannot stat: {e}"))?;
+        if !meta.is_file() {
+            return Err("no longer a regular file".to_string());
+        }
+        if meta.ino() != self.inode {
+            return Err("inode changed".to_string());
+        }
+        if meta.len() != self.size {
+            return Err("size changed".to_string());
+        }
+        if meta.mtime() != self.mtime || meta.mtime_nsec() != self.mtime_nsec {
+            return Err("modification time changed".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Writes the files selected for deletion and their sidecars to `output`.
+///
+/// The roots are expected to be canonical so the plan can be applied from any
+/// working directory.
+pub fn write_plan(
+    raw_root: &Path,
+    compressed_root: &Path,
+    matcher: &Matcher,
+    mode: DeleteMode,
+    selection: &Selection,
+    registry: &Registry,
+    output: &Path,
+) -> io::Result<()> {
+    let mut sidecars = Sidecars::new(registry);
+    let mut entries = Vec::with_capacity(selection.to_delete.len());
+    for candidate in &selection.to_delete {
+        let group = sidecars.take(&candidate.path).unwrap_or_else(|e| {
+            eprintln!(
+                "  Warning: Cannot look for sidecars of {}, they stay in place: {}",
+                candidate.path.display(),
+                e
+            );
+            Vec::new()
+        });
+        entries.push(PlanEntry {
+            file: FileState::read(&candidate.path)?,
+            raw: candidate.raw.clone(),
+            sidecars: group
+                .iter()
+                .map(|sidecar| FileState::read(sidecar))
+                .collect::<io::Result<_>>()?,
+        });
+    }
+
+    let plan = Plan {
+        mode,
+        raw_root: raw_root.to_path_buf(),
+        compressed_root: compressed_root.to_path_buf(),
+        matcher: matcher.clone(),
+        created: Local::now().to_rfc3339(),
+        counts: selection.counts.clone(),
+        allow_ambiguous: selection.allow_ambiguous,
+        entries,
+    };
+    let mut json = serde_json::to_string_pretty(&plan)?;
+    json.push('\n');
+    fs::write(output, json)
+}
+
+/// Deletes the files recorded in the plan at `plan_path` that are unchanged.
+pub fn apply(plan_path: &Path, options: &DeleteOptions) {
+    let plan: Plan = match fs::read_to_string(plan_path)
+        .and_then(|json| serde_json::from_str(&json).map_err(io::Error::from))
+    {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("Error: Cannot read plan {}: {}", plan_path.display(), e);
+            process::exit(1);
+        }
+    };
+
+    if let DeleteBackend::Quarantine(quarantine_dir) = &options.backend
+        && (is_within(quarantine_dir, &plan.raw_root)
+            || is_within(quarantine_dir, &plan.compressed_root))
+    {
+        eprintln!(
+            "Error: Quarantine directory must not be inside the raw or compressed directory: {}",
+            quarantine_dir.display()
+        );
+        process::exit(1);
+    }
+
+    println!(
+        "Applying plan {} created {} ({} files)",
+        plan_path.display(),
+        plan.created,
+        plan.entries.len()
+    );
+    println!("  {}", Labels::of(plan.mode).deleting);
+
+    if plan.entries.is_empty() {
+        println!("\nNo files to delete. The plan is empty.");
+        return;
+    }
+    if plan.counts.unmatched().is_none() {
+        eprintln!(
+            "Error: The counts of plan {} do not add up",
+            plan_path.display()
+        );
+        process::exit(1);
+    }
+    let root = match plan.mode {
+        DeleteMode::Orphaned | DeleteMode::Matched => &plan.compressed_root,
+        DeleteMode::RawOrphaned | DeleteMode::RawMatched => &plan.raw_root,
+    };
+    delete::enforce_limits(
+        plan.entries.len(),
+        plan.counts.total,
+        plan.mode,
+        &plan.counts.summary(plan.mode),
+        options,
+    );
+
+    let files: Vec<PathBuf> = plan.entries.iter().map(|e| e.file.path.clone()).collect();
+    let entries: HashMap<&Path, &PlanEntry> = plan
+        .entries
+        .iter()
+        .map(|entry| (entry.file.path.as_path(), entry))
+        .collect();
+    let sidecars: HashMap<&Path, &FileState> = plan
+        .entries
+        .iter()
+        .flat_map(|entry| &entry.sidecars)
+        .map(|sidecar| (sidecar.path.as_path(), sidecar))
+        .collect();
+
+    // When a RAW may appear anywhere in the tree or under another name, the
+    // tree is indexed again right before deleting.
+    let raw_index = (matches!(plan.mode, DeleteMode::Orphaned | DeleteMode::Matched)
+        && plan.matcher.can_be_ambiguous())
+    .then(|| plan.matcher.index(&plan.raw_root).0);
+
+    // A RAW may have gained a compressed file anywhere the rules look, so
+    // both trees are scanned again.
+    let claims = matches!(plan.mode, DeleteMode::RawOrphaned | DeleteMode::RawMatched).then(|| {
+        let (raw_index, _) = plan.matcher.index(&plan.raw_root);
+        let (compressed_files, walk_errors) =
+            get_compressed_files(&plan.compressed_root, &plan.matcher);
+        claim_raws(
+            &raw_index,
+            &plan.compressed_root,
+            &compressed_files,
+            &walk_errors,
+        )
+    });
+
+    let mut protection = Protection::new(root);
+    delete::delete_files(&files, root, options, |file| {
+        if let Some(reason) = protection.reason(file) {
+            return Err(format!("protected by {reason}"));
+        }
+        match (entries.get(file), sidecars.get(file)) {
+            (Some(entry), _) => recheck(&plan, raw_index.as_ref(), claims.as_ref(), entry),
+            (None, Some(sidecar)) => sidecar.check(),
+            (None, None) => Err("not in the plan".to_string()),
+        }
+    });
+}
+
+/// Verifies that `entry` is still in the state it was planned in.
+fn recheck(
+    plan: &Plan,
+    raw_index: Option<&RawIndex>,
+    claims: Option<&Claims>,
+    entry: &PlanEntry,
+) -> Result<(), String> {
+    let (root, name) = match plan.mode {
+        DeleteMode::Orphaned | DeleteMode::Matched => (&plan.compressed_root, "compressed"),
+        DeleteMode::RawOrphaned | DeleteMode::RawMatched => (&plan.raw_root, "raw"),
+    };
+    if entry
+        .file
+        .path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err("the path contains `..`".to_string());
+    }
+    if !entry.file.path.starts_with(root) {
+        return Err(format!("not below the {name} root of the plan"));
+    }
+    entry.file.check()?;
+
+    let find_matching_raw = || match raw_index {
+        Some(raw_index) => raw_index.find_matching_raw(&entry.file.path, &plan.compressed_root),
+        None => {
+            plan.matcher
+                .find_matching_raw(&entry.file.path, &plan.compressed_root, &plan.raw_root)
+        }
+    };
+    match plan.mode {
+        DeleteMode::Matched => {
+            let Some(planned) = &entry.raw else {
+                return Err("no RAW recorded for a matched entry".to_string());
+            };
+            // The lookup has to find the recorded RAW again, so a plan cannot
+            // name a RAW of its own.
+            match find_matching_raw() {
+                RawMatch::Matched { raw, .. } if &raw == planned => Ok(()),
+                RawMatch::Ambiguous(raws) if plan.allow_ambiguous && raws.contains(planned) => {
+                    Ok(())
+                }
+                RawMatch::Matched { raw, .. } => Err(format!(
+                    "it now matches RAW {} instead of {}",
+                    raw.display(),
+                    planned.display()
+                )),
+                RawMatch::Ambiguous(_) => Err("it now has several candidate RAWs".to_string()),
+                RawMatch::NotMatched => Err(format!(
+                    "no RAW matches it anymore, the plan recorded {}",
+                    planned.display()
+                )),
+                RawMatch::Unknown(e) => {
+                    Err(format!("cannot check RAW {}: {}", planned.display(), e))
+                }
+            }
+        }
+        DeleteMode::Orphaned => match find_matching_raw() {
+            RawMatch::Matched { raw, .. } => Err(format!("RAW {} appeared", raw.display())),
+            RawMatch::Ambiguous(raws) => Err(format!(
+                "several candidate RAWs appeared, e.g. {}",
+                raws[0].display()
+            )),
+            RawMatch::NotMatched => Ok(()),
+            RawMatch::Unknown(e) => Err(format!("cannot check for a RAW: {e}")),
+        },
+        DeleteMode::RawOrphaned => match claims.map(|claims| claims.status(&entry.file.path)) {
+            Some(RawStatus::Orphaned) => Ok(()),
+            Some(RawStatus::Matched(compressed_file)) => Err(format!(
+                "compressed file {} appeared",
+                compressed_file.display()
+            )),
+            Some(RawStatus::Ambiguous) => {
+                Err("now one of several candidates of a compressed file".to_string())
+            }
+            Some(RawStatus::Undetermined(reason)) => {
+                Err(format!("cannot check for a compressed file: {reason}"))
+            }
+            None => Err("the compressed tree was not scanned".to_string()),
+        },
+        DeleteMode::RawMatched => match claims.map(|claims| claims.status(&entry.file.path)) {
+            Some(RawStatus::Matched(_)) => Ok(()),
+            Some(RawStatus::Orphaned) => Err("its compressed file disappeared".to_string()),
+            Some(RawStatus::Ambiguous) => {
+                Err("now one of several candidates of a compressed file".to_string())
+            }
+            Some(RawStatus::Undetermined(reason)) => {
+                Err(format!("cannot check for a compressed file: {reason}"))
+            }
+            None => Err("the compressed tree was not scanned".to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use super::*;
+    use crate::select::{Candidate, Counts};
+
+    #[test]
+    fn non_utf8_paths_survive_the_plan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join(OsStr::from_bytes(b"caf\xe9.jpg"));
+        let raw = tmp.path().join(OsStr::from_bytes(b"caf\xe9.RAF"));
+        let sidecar = tmp.path().join(OsStr::from_bytes(b"caf\xe9.jpg.xmp"));
+        for path in [&file, &raw, &sidecar] {
+            fs::write(path, "data").unwrap();
+        }
+        let selection = Selection {
+            counts: Counts {
+                total: 1,
+                matched: 1,
+                ..Counts::default()
+            },
+            unreadable: 0,
+            allow_ambiguous: false,
+            to_delete: vec![Candidate {
+                path: file.clone(),
+                raw: Some(raw.clone()),
+            }],
+        };
+        let output = tmp.path().join("plan.json");
+        write_plan(
+            tmp.path(),
+            tmp.path(),
+            &Matcher::default(),
+            DeleteMode::Matched,
+            &selection,
+            &Registry::new(&[]).unwrap(),
+            &output,
+        )
+        .unwrap();
+
+        let plan: Plan = serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        let entry = &plan.entries[0];
+        assert_eq!(entry.file.path, file);
+        assert_eq!(entry.raw.as_ref(), Some(&raw));
+        assert_eq!(entry.sidecars[0].path, sidecar);
+        assert!(entry.file.check().is_ok());
+    }
+}
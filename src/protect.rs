@@ -0,0 +1,150 @@
+//! Per-directory protection of files that must never be deleted.
+//!
+//! A `.photocleanupignore` file holds gitignore-style patterns, relative to its
+//! directory, for files below it that are protected; `!pattern` lifts the
+//! protection again, and deeper files take precedence like in git. A
+//! `.nocleanup` file protects its whole directory tree.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use ignore::{
+    Match,
+    gitignore::{Gitignore, GitignoreBuilder},
+};
+
+pub const IGNORE_FILE: &str = ".photocleanupignore";
+pub const NO_CLEANUP_FILE: &str = ".nocleanup";
+
+/// The parsed ignore file of a directory.
+enum Rules {
+    Patterns(Gitignore),
+    /// The file could not be read or parsed, so everything below it is protected.
+    Broken(String),
+}
+
+/// Answers whether files below a root are protected, caching the rules per directory.
+pub struct Protection {
+    root: PathBuf,
+    ignore_files: HashMap<PathBuf, Option<Rc<Rules>>>,
+    no_cleanup: HashMap<PathBuf, bool>,
+}
+
+impl Protection {
+    pub fn new(root: &Path) -> Protection {
+        Protection {
+            root: root.to_path_buf(),
+            ignore_files: HashMap::new(),
+            no_cleanup: HashMap::new(),
+        }
+    }
+
+    /// Returns why `path` must not be deleted, or `None` if it may be.
+    pub fn reason(&mut self, path: &Path) -> Option<String> {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return None;
+        };
+        let directories: Vec<PathBuf> = relative
+            .ancestors()
+            .skip(1)
+            .map(|ancestor| self.root.join(ancestor))
+            .collect();
+
+        for directory in &directories {
+            if self.has_no_cleanup(directory) {
+                return Some(format!("{} in {}", NO_CLEANUP_FILE, directory.display()));
+            }
+        }
+
+        // The deepest ignore file with an opinion on the path decides.
+        for directory in &directories {
+            let Some(rules) = self.ignore_file(directory) else {
+                continue;
+            };
+            let gitignore = match rules.as_ref() {
+                Rules::Patterns(gitignore) => gitignore,
+                Rules::Broken(e) => {
+                    return Some(format!(
+                        "unusable {} in {}: {}",
+                        IGNORE_FILE,
+                        directory.display(),
+                        e
+                    ));
+                }
+            };
+            match gitignore.matched_path_or_any_parents(path, false) {
+                Match::Ignore(glob) => {
+                    return Some(format!(
+                        "{} in {}: {}",
+                        IGNORE_FILE,
+                        directory.display(),
+                        glob.original()
+                    ));
+                }
+                Match::Whitelist(_) => return None,
+                Match::None => {}
+            }
+        }
+
+        None
+    }
+
+    /// Removes the protected paths from `files` and returns how many there were.
+    ///
+    /// With `verbose`, prints each protected path and the reason.
+    pub fn drop_protected<T: AsRef<Path>>(&mut self, files: &mut Vec<T>, verbose: bool) -> usize {
+        let before = files.len();
+        files.retain(|file| match self.reason(file.as_ref()) {
+            Some(reason) => {
+                if verbose {
+                    println!("PROTECTED {} ({})", file.as_ref().display(), reason);
+                }
+                false
+            }
+            None => true,
+        });
+        before - files.len()
+    }
+
+    fn has_no_cleanup(&mut self, directory: &Path) -> bool {
+        *self
+            .no_cleanup
+            .entry(directory.to_path_buf())
+            .or_insert_with(|| {
+                // A marker that cannot be checked protects just like an existing one.
+                !matches!(directory.join(NO_CLEANUP_FILE).try_exists(), Ok(false))
+            })
+    }
+
+    fn ignore_file(&mut self, directory: &Path) -> Option<Rc<Rules>> {
+        self.ignore_files
+            .entry(directory.to_path_buf())
+            .or_insert_with(|| {
+                let file = directory.join(IGNORE_FILE);
+                if let Ok(false) = file.try_exists() {
+                    return None;
+                }
+                let mut builder = GitignoreBuilder::new(directory);
+                let rules = match builder.add(&file) {
+                    Some(e) => Rules::Broken(e.to_string()),
+                    None => match builder.build() {
+                        Ok(gitignore) => Rules::Patterns(gitignore),
+                        Err(e) => Rules::Broken(e.to_string()),
+                    },
+                };
+                if let Rules::Broken(e) = &rules {
+                    eprintln!(
+                        "Warning: Protecting everything below {} because {} is unusable: {}",
+                        directory.display(),
+                        IGNORE_FILE,
+                        e
+                    );
+                }
+                Some(Rc::new(rules))
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,399 @@
+//! Quarantine runs: a grace period between selecting files and removing them.
+//!
+//! Every run with `--quarantine` gets its own dated folder below the quarantine
+//! directory. Files are moved into its `files/` folder keeping their path
+//! relative to the cleaned root, and `manifest.jsonl` records where they came
+//! from, so `restore` can put them back and `purge` can expire old runs.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::fsutil;
+
+const MANIFEST: &str = "manifest.jsonl";
+const FILES: &str = "files";
+
+/// The first line of a manifest.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// The absolute root the quarantined paths are relative to.
+    #[serde(with = "fsutil::lossless")]
+    root: PathBuf,
+    /// When the run was started, in RFC 3339 format.
+    created: String,
+}
+
+/// Every following line of a manifest, one per quarantined file.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "fsutil::lossless")]
+    path: PathBuf,
+}
+
+/// A quarantine run that files are being moved into.
+pub struct Run {
+    id: String,
+    dir: PathBuf,
+    root: PathBuf,
+    manifest: File,
+}
+
+impl Run {
+    /// Creates a new run folder below `quarantine_dir` for files from `root`.
+    pub fn create(quarantine_dir: &Path, root: &Path) -> io::Result<Run> {
+        fs::create_dir_all(quarantine_dir)?;
+        let root = fs::canonicalize(root)?;
+
+        let (id, dir) = loop {
+            let id = next_run_id(quarantine_dir);
+            let dir = quarantine_dir.join(&id);
+            match fs::create_dir(&dir) {
+                Ok(()) => break (id, dir),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut manifest = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(dir.join(MANIFEST))?;
+        let header = Header {
+            root: root.clone(),
+            created: Local::now().to_rfc3339(),
+        };
+        writeln!(manifest, "{}", serde_json::to_string(&header)?)?;
+
+        Ok(Run {
+            id,
+            dir,
+            root,
+            manifest,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Moves `path` into the run and returns its new location.
+    pub fn quarantine(&mut self, path: &Path) -> io::Result<PathBuf> {
+        let absolute = fs::canonicalize(path.parent().unwrap_or(Path::new(".")))?
+            .join(path.file_name().unwrap_or_default());
+        let relative = absolute.strip_prefix(&self.root).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not below {}", path.display(), self.root.display()),
+            )
+        })?;
+
+        // Record the file before it is moved, so an interrupted run can still
+        // be restored; `restore` skips entries whose file never arrived.
+        let entry = Entry {
+            path: relative.to_path_buf(),
+        };
+        writeln!(self.manifest, "{}", serde_json::to_string(&entry)?)?;
+        self.manifest.flush()?;
+
+        let destination = self.dir.join(FILES).join(relative);
+        fsutil::move_file(&absolute, &destination)?;
+        Ok(destination)
+    }
+}
+
+/// Returns the location `path` below `root` would get in a new run.
+pub fn preview_destination(quarantine_dir: &Path, root: &Path, path: &Path) -> PathBuf {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    quarantine_dir
+        .join(next_run_id(quarantine_dir))
+        .join(FILES)
+        .join(relative)
+}
+
+/// Returns a run id based on the current time that is not taken yet.
+fn next_run_id(quarantine_dir: &Path) -> String {
+    let base = Local::now().format("%Y-%m-%d_%H%M%S").to_string();
+    if !quarantine_dir.join(&base).exists() {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{base}-{n}"))
+        .find(|id| !quarantine_dir.join(id).exists())
+        .unwrap_or(base)
+}
+
+fn read_manifest(run_dir: &Path) -> io::Result<(Header, Vec<Entry>)> {
+    let reader = BufReader::new(File::open(run_dir.join(MANIFEST))?);
+    let mut lines = reader.lines();
+
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "manifest is empty",
+            ));
+        }
+    };
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+
+    Ok((header, entries))
+}
+
+/// Moves the files of run `run_id` back to their original locations.
+///
+/// Files whose original location is taken again are left in quarantine, and the
+/// run is only removed once nothing is left in it.
+pub fn restore(quarantine_dir: &Path, run_id: &str, dry_run: bool, verbose: bool) {
+    let run_dir = quarantine_dir.join(run_id);
+    let (header, entries) = match read_manifest(&run_dir) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!(
+                "Error: Cannot read quarantine run {}: {}",
+                run_dir.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    println!(
+        "Restoring {} files from {} to {}...",
+        entries.len(),
+        run_dir.display(),
+        header.root.display()
+    );
+
+    let mut restored = 0usize;
+    let mut missing = 0usize;
+    let mut conflicts = Vec::new();
+    let mut errors = Vec::new();
+
+    for entry in &entries {
+        let source = run_dir.join(FILES).join(&entry.path);
+        let destination = header.root.join(&entry.path);
+
+        if !source.exists() {
+            missing += 1;
+            if verbose {
+                println!("  Missing from quarantine: {}", source.display());
+            }
+            continue;
+        }
+        if destination.exists() {
+            eprintln!("  Not restoring, already exists: {}", destination.display());
+            conflicts.push(destination);
+            continue;
+        }
+
+        if dry_run {
+            println!("  {} -> {}", source.display(), destination.display());
+            restored += 1;
+            continue;
+        }
+        match fsutil::move_file(&source, &destination) {
+            Ok(()) => {
+                restored += 1;
+                if verbose {
+                    println!("  Restored: {}", destination.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("  Error restoring {}: {}", destination.display(), e);
+                errors.push(destination);
+            }
+        }
+    }
+
+    println!("\nSummary:");
+    if dry_run {
+        println!("  Files that would be restored: {}", restored);
+    } else {
+        println!("  Files restored: {}", restored);
+    }
+    println!("  Files missing from quarantine: {}", missing);
+    println!("  Files with an existing original: {}", conflicts.len());
+    println!("  Errors: {}", errors.len());
+
+    if dry_run {
+        return;
+    }
+    if conflicts.is_empty() && errors.is_empty() {
+        // Only what the manifest lists was restored, so anything else left in
+        // the run is kept rather than deleted with it.
+        let files_dir = run_dir.join(FILES);
+        remove_empty_dirs(&files_dir);
+        if files_dir.symlink_metadata().is_ok() {
+            eprintln!(
+                "\nWarning: Kept quarantine run {} because files not in its manifest are left in {}",
+                run_id,
+                files_dir.display()
+            );
+            return;
+        }
+        match fs::remove_file(run_dir.join(MANIFEST)).and_then(|()| fs::remove_dir(&run_dir)) {
+            Ok(()) => println!("\nRemoved quarantine run {}", run_id),
+            Err(e) => eprintln!("\nError removing {}: {}", run_dir.display(), e),
+        }
+    } else {
+        println!(
+            "\nKept quarantine run {} because some files could not be restored",
+            run_id
+        );
+    }
+}
+
+/// Removes `dir` and the directories below it that are empty, deepest first.
+fn remove_empty_dirs(dir: &Path) {
+    for entry in WalkDir::new(dir).contents_first(true).into_iter().flatten() {
+        if entry.file_type().is_dir() {
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+}
+
+/// Removes all runs below `quarantine_dir` that were created more than
+/// `older_than_days` days ago.
+pub fn purge(quarantine_dir: &Path, older_than_days: u32, dry_run: bool, verbose: bool) {
+    let runs = match fs::read_dir(quarantine_dir) {
+        Ok(runs) => runs,
+        Err(e) => {
+            eprintln!(
+                "Error: Cannot read quarantine directory {}: {}",
+                quarantine_dir.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    // An age reaching back before the earliest representable date leaves no
+    // cutoff, and no run is older than that.
+    let cutoff = chrono::Duration::try_days(older_than_days.into())
+        .and_then(|age| Local::now().checked_sub_signed(age));
+    let mut expired = Vec::new();
+    let mut kept = 0usize;
+
+    for run in runs.filter_map(|e| e.ok()) {
+        let run_dir = run.path();
+        if !run_dir.join(MANIFEST).is_file() {
+            continue;
+        }
+        let created = read_manifest(&run_dir)
+            .ok()
+            .and_then(|(header, _)| DateTime::parse_from_rfc3339(&header.created).ok());
+        match created {
+            Some(created) if cutoff.is_some_and(|cutoff| created < cutoff) => expired.push(run_dir),
+            Some(_) => kept += 1,
+            None => eprintln!(
+                "  Skipping run with unreadable manifest: {}",
+                run_dir.display()
+            ),
+        }
+    }
+    expired.sort();
+
+    let mut errors = 0usize;
+    if dry_run {
+        println!("Dry run mode - runs that would be purged:");
+        for run_dir in &expired {
+            println!("  {}", run_dir.display());
+        }
+    } else {
+        for run_dir in &expired {
+            match fs::remove_dir_all(run_dir) {
+                Ok(()) => {
+                    if verbose {
+                        println!("  Purged: {}", run_dir.display());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("  Error purging {}: {}", run_dir.display(), e);
+                    errors += 1;
+                }
+            }
+        }
+    }
+
+    println!("\nSummary:");
+    if dry_run {
+        println!("  Runs that would be purged: {}", expired.len());
+    } else {
+        println!("  Runs purged: {}", expired.len() - errors);
+    }
+    println!("  Runs kept: {}", kept);
+    if errors > 0 {
+        eprintln!("\nEncountered {} errors during purge", errors);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use super::*;
+
+    #[test]
+    fn non_utf8_names_are_recorded_and_restored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("jpg");
+        let quarantine_dir = tmp.path().join("quarantine");
+        let file = root.join("a").join(OsStr::from_bytes(b"caf\xe9.jpg"));
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "jpeg").unwrap();
+
+        let mut run = Run::create(&quarantine_dir, &root).unwrap();
+        let destination = run.quarantine(&file).unwrap();
+        assert!(destination.is_file() && !file.exists());
+        let (_, entries) = read_manifest(&quarantine_dir.join(run.id())).unwrap();
+        assert_eq!(
+            entries[0].path,
+            Path::new("a").join(OsStr::from_bytes(b"caf\xe9.jpg"))
+        );
+
+        restore(&quarantine_dir, run.id(), false, false);
+        assert_eq!(fs::read(&file).unwrap(), b"jpeg");
+        assert!(!quarantine_dir.join(run.id()).exists());
+    }
+
+    #[test]
+    fn restore_keeps_files_missing_from_the_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("jpg");
+        let quarantine_dir = tmp.path().join("quarantine");
+        let file = root.join("a.jpg");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&file, "jpeg").unwrap();
+
+        let mut run = Run::create(&quarantine_dir, &root).unwrap();
+        run.quarantine(&file).unwrap();
+        let run_dir = quarantine_dir.join(run.id());
+        let stray = run_dir.join(FILES).join("b").join("stray.jpg");
+        fs::create_dir_all(stray.parent().unwrap()).unwrap();
+        fs::write(&stray, "jpeg").unwrap();
+
+        restore(&quarantine_dir, run.id(), false, false);
+        assert!(file.is_file());
+        assert!(stray.is_file());
+        assert!(run_dir.join(MANIFEST).is_file());
+
+        fs::remove_file(&stray).unwrap();
+        restore(&quarantine_dir, run.id(), false, false);
+        assert!(!run_dir.exists());
+    }
+}
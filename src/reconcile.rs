@@ -0,0 +1,262 @@
+//! Moving compressed files back next to RAWs that were moved elsewhere.
+//!
+//! A compressed file whose RAW is no longer at the mirrored path, but is found
+//! by stem anywhere below the RAW root, goes to the path that mirrors the RAW's
+//! new directory. Nothing is deleted, and nothing is guessed: ambiguous files
+//! and files that would collide stay where they are.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process,
+};
+
+use chrono::Local;
+use serde::Serialize;
+
+use crate::{
+    fsutil,
+    matching::{Matcher, RawMatch},
+    moves::{Group, MoveOptions, MovePlan},
+    select::{add_walk_errors, display_paths, get_compressed_files, print_walk_errors},
+    sidecars::{Registry, Sidecars},
+};
+
+/// The first line of a manifest.
+#[derive(Serialize)]
+struct Header {
+    #[serde(with = "fsutil::lossless")]
+    raw_root: PathBuf,
+    #[serde(with = "fsutil::lossless")]
+    compressed_root: PathBuf,
+    /// When the run was started, in RFC 3339 format.
+    created: String,
+}
+
+/// Moves the compressed files below `compressed_root` whose RAW was found
+/// elsewhere below `raw_root` to the mirrored path of that RAW, with their
+/// sidecars.
+///
+/// `matcher` must match anywhere. Refuses to move anything when part of either
+/// tree cannot be read, since a RAW there could make a match ambiguous.
+pub fn reconcile(
+    matcher: &Matcher,
+    raw_root: &Path,
+    compressed_root: &Path,
+    registry: &Registry,
+    options: &MoveOptions,
+) {
+    println!(
+        "Scanning for compressed files in {}...",
+        compressed_root.display()
+    );
+    let (compressed_files, mut walk_errors) = get_compressed_files(compressed_root, matcher);
+    println!("Found {} compressed files", compressed_files.len());
+    println!("Indexing RAW files in {}...", raw_root.display());
+    let (raw_index, raw_walk_errors) = matcher.index(raw_root);
+    add_walk_errors(&mut walk_errors, raw_walk_errors);
+    if !walk_errors.is_empty() {
+        eprintln!(
+            "\nError: {} paths could not be read, not moving anything:",
+            walk_errors.len()
+        );
+        print_walk_errors(&walk_errors);
+        process::exit(1);
+    }
+
+    let mut plan = MovePlan::default();
+    let mut moves = Vec::new();
+    let mut without_raw = 0usize;
+    let mut ambiguous = Vec::new();
+    let mut undetermined = Vec::new();
+    for compressed_file in &compressed_files {
+        match raw_index.find_matching_raw(compressed_file, compressed_root) {
+            RawMatch::Matched { raw, .. } => {
+                if raw_index.is_mirrored(compressed_file, compressed_root, &raw) {
+                    plan.in_place += 1;
+                    continue;
+                }
+                let Some(target_dir) = raw
+                    .parent()
+                    .and_then(|raw_dir| raw_dir.strip_prefix(raw_root).ok())
+                    .and_then(|dir| matcher.layout.strip_folder(dir, true))
+                    .map(|dir| compressed_root.join(matcher.layout.add_folder(&dir, false)))
+                else {
+                    continue;
+                };
+                let target = target_dir.join(compressed_file.file_name().unwrap_or_default());
+                if !raw_index.is_mirrored(&target, compressed_root, &raw) {
+                    // The mapping rules send the mirrored path somewhere else.
+                    plan.conflicts.push((
+                        compressed_file.clone(),
+                        target,
+                        format!("the mapping rules would not pair it with {}", raw.display()),
+                    ));
+                    continue;
+                }
+                if options.verbose {
+                    println!(
+                        "MOVE {} -> {} (RAW {})",
+                        compressed_file.display(),
+                        target.display(),
+                        raw.display()
+                    );
+                }
+                moves.push((compressed_file.clone(), target_dir));
+            }
+            RawMatch::NotMatched => {
+                if options.verbose {
+                    println!("NO_MATCH {}", compressed_file.display());
+                }
+                without_raw += 1;
+            }
+            RawMatch::Ambiguous(raws) => {
+                if options.verbose {
+                    println!(
+                        "AMBIGUOUS {} -> {}",
+                        compressed_file.display(),
+                        display_paths(&raws)
+                    );
+                }
+                ambiguous.push((compressed_file.clone(), raws));
+            }
+            RawMatch::Unknown(e) => {
+                if options.verbose {
+                    println!("UNKNOWN {} ({})", compressed_file.display(), e);
+                }
+                undetermined.push((compressed_file.clone(), e));
+            }
+        }
+    }
+
+    let mut sidecars = Sidecars::new(registry);
+    let mut groups = Vec::new();
+    let mut errors = 0usize;
+    for (compressed_file, target_dir) in moves {
+        match sidecars.take(&compressed_file) {
+            Ok(group_sidecars) => groups.push(Group::new(
+                compressed_file,
+                false,
+                group_sidecars,
+                &target_dir,
+            )),
+            Err(e) => {
+                eprintln!(
+                    "  Error: Cannot look for sidecars of {}, leaving it in place: {}",
+                    compressed_file.display(),
+                    e
+                );
+                errors += 1;
+            }
+        }
+    }
+    // Which of several files with the same destination belongs there is a
+    // guess, so none of them is moved.
+    let mut wanted: HashMap<PathBuf, usize> = HashMap::new();
+    for group in &groups {
+        *wanted.entry(group.destination().to_path_buf()).or_default() += 1;
+    }
+    for group in groups {
+        if wanted[group.destination()] > 1 {
+            plan.conflicts.push((
+                group.image().to_path_buf(),
+                group.destination().to_path_buf(),
+                "also wanted by another file".to_string(),
+            ));
+        } else {
+            plan.add(group);
+        }
+    }
+
+    let header = Header {
+        raw_root: std::path::absolute(raw_root).unwrap_or(raw_root.to_path_buf()),
+        compressed_root: std::path::absolute(compressed_root)
+            .unwrap_or(compressed_root.to_path_buf()),
+        created: Local::now().to_rfc3339(),
+    };
+    let moved = plan.execute(&header, "reconcile", &[compressed_root], options);
+
+    println!("\nSummary:");
+    println!("  Total compressed files: {}", compressed_files.len());
+    println!("  Files already next to their RAW: {}", plan.in_place);
+    let verb = if options.dry_run {
+        "that would be moved"
+    } else {
+        "moved"
+    };
+    println!("  Files {}: {}", verb, moved.compressed);
+    println!("  Sidecars {}: {}", verb, moved.sidecars);
+    println!("  Files without a RAW anywhere: {}", without_raw);
+    println!(
+        "  Files with ambiguous RAW (left in place): {}",
+        ambiguous.len()
+    );
+    println!(
+        "  Files with undetermined RAW status (left in place): {}",
+        undetermined.len()
+    );
+    println!(
+        "  Files left in place because of conflicts: {}",
+        plan.conflicts.len()
+    );
+    println!("  Errors: {}", errors + moved.errors);
+
+    if !ambiguous.is_empty() {
+        println!("\nThese files have several candidate RAWs and were left in place:");
+        for (compressed_file, raws) in &ambiguous {
+            println!("  {} -> {}", compressed_file.display(), display_paths(raws));
+        }
+    }
+    if !undetermined.is_empty() {
+        println!("\nThese files could not be checked and were left in place:");
+        for (compressed_file, e) in &undetermined {
+            println!("  {} ({})", compressed_file.display(), e);
+        }
+    }
+    plan.print_conflicts();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+
+    use super::*;
+
+    #[test]
+    fn non_utf8_files_move_with_their_sidecars() {
+        let tmp = tempfile::tempdir().unwrap();
+        let raw_root = tmp.path().join("raw");
+        let compressed_root = tmp.path().join("jpg");
+        let name = |ext: &str| OsStr::from_bytes(&[b"B\xe9.", ext.as_bytes()].concat()).to_owned();
+        fs::create_dir_all(raw_root.join("2024/b")).unwrap();
+        fs::create_dir_all(compressed_root.join("2024/a")).unwrap();
+        fs::write(raw_root.join("2024/b").join(name("RAF")), "raw").unwrap();
+        fs::write(compressed_root.join("2024/a").join(name("JPG")), "jpeg").unwrap();
+        fs::write(compressed_root.join("2024/a").join(name("JPG.xmp")), "xmp").unwrap();
+
+        let matcher = Matcher {
+            match_anywhere: true,
+            ..Matcher::default()
+        };
+        let manifest = tmp.path().join("manifest.jsonl");
+        let options = MoveOptions {
+            dry_run: false,
+            verbose: false,
+            manifest: Some(manifest.clone()),
+        };
+        reconcile(
+            &matcher,
+            &raw_root,
+            &compressed_root,
+            &Registry::new(&[]).unwrap(),
+            &options,
+        );
+
+        let target = compressed_root.join("2024/b");
+        assert_eq!(fs::read(target.join(name("JPG"))).unwrap(), b"jpeg");
+        assert_eq!(fs::read(target.join(name("JPG.xmp"))).unwrap(), b"xmp");
+        assert!(!compressed_root.join("2024/a").exists());
+        // The header and one entry per moved file.
+        assert_eq!(fs::read_to_string(&manifest).unwrap().lines().count(), 3);
+    }
+}
@@ -0,0 +1,177 @@
+//! Moving a library between the parallel, mixed and sibling layouts.
+//!
+//! RAW and compressed files keep their path relative to the root, without
+//! the sibling folders of the old layout and with those of the new one.
+
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
+
+use chrono::Local;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::{
+    formats::{CompressedFormats, RawFormats},
+    layout::{Layout, LayoutKind},
+    moves::{Group, MoveOptions, MovePlan},
+    sidecars::{Registry, Sidecars},
+    trash,
+};
+
+/// A library in one layout.
+pub struct Tree {
+    pub layout: Layout,
+    /// The RAW root, which is the library root in single-root layouts.
+    pub raw_root: PathBuf,
+    /// The compressed root, which is the library root in single-root layouts.
+    pub compressed_root: PathBuf,
+}
+
+impl Tree {
+    fn root(&self, raw: bool) -> &Path {
+        if raw {
+            &self.raw_root
+        } else {
+            &self.compressed_root
+        }
+    }
+}
+
+/// The first line of a manifest.
+#[derive(Serialize)]
+struct Header {
+    from: LayoutKind,
+    to: LayoutKind,
+    /// When the run was started, in RFC 3339 format.
+    created: String,
+}
+
+/// Moves the RAW and compressed files of `source` and their sidecars to where
+/// they belong in `target`.
+///
+/// Files whose destination is taken stay where they are, together with their
+/// sidecars. Other files are not touched.
+pub fn relayout(
+    source: &Tree,
+    target: &Tree,
+    raw_formats: &RawFormats,
+    compressed_formats: &CompressedFormats,
+    registry: &Registry,
+    options: &MoveOptions,
+) {
+    let single_root = source.raw_root == source.compressed_root;
+    let mut images: Vec<(PathBuf, bool)> = Vec::new();
+    let mut total_files = 0usize;
+    let mut walk_errors = Vec::new();
+    let roots = if single_root {
+        vec![&source.raw_root]
+    } else {
+        vec![&source.raw_root, &source.compressed_root]
+    };
+    for root in roots {
+        println!("Scanning {}...", root.display());
+        for entry in trash::skip_trash(WalkDir::new(root)) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    walk_errors.push(e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            total_files += 1;
+            let path = entry.path();
+            let Ok(relative) = path.strip_prefix(root) else {
+                continue;
+            };
+            let is_raw = raw_formats.is_raw(path)
+                && (single_root || root == &source.raw_root)
+                && source.layout.includes_raw(relative);
+            let is_compressed = compressed_formats.is_compressed(path)
+                && (single_root || root == &source.compressed_root)
+                && source.layout.includes_compressed(relative);
+            if is_raw || is_compressed {
+                images.push((path.to_path_buf(), is_raw));
+            }
+        }
+    }
+    if !walk_errors.is_empty() {
+        eprintln!(
+            "\nError: {} paths could not be read, not moving anything:",
+            walk_errors.len()
+        );
+        for e in &walk_errors {
+            eprintln!("  {}", e);
+        }
+        process::exit(1);
+    }
+    // Compressed files go first, so sidecars named after a stem that both
+    // files share end up with the RAW.
+    images.sort_by(|(a, a_raw), (b, b_raw)| a_raw.cmp(b_raw).then(a.cmp(b)));
+
+    let mut sidecars = Sidecars::new(registry);
+    let mut plan = MovePlan::default();
+    let mut errors = 0usize;
+    let mut grouped = 0usize;
+    for (image, raw) in images {
+        let group_sidecars = match sidecars.take(&image) {
+            Ok(group_sidecars) => group_sidecars,
+            Err(e) => {
+                eprintln!(
+                    "  Error: Cannot look for sidecars of {}, leaving it in place: {}",
+                    image.display(),
+                    e
+                );
+                errors += 1;
+                continue;
+            }
+        };
+        grouped += 1 + group_sidecars.len();
+        let Some(dir) = image
+            .parent()
+            .and_then(|image_dir| image_dir.strip_prefix(source.root(raw)).ok())
+            .and_then(|dir| source.layout.strip_folder(dir, raw))
+        else {
+            continue;
+        };
+        let target_dir = target.root(raw).join(target.layout.add_folder(&dir, raw));
+        plan.add(Group::new(image, raw, group_sidecars, &target_dir));
+    }
+
+    let header = Header {
+        from: source.layout.kind,
+        to: target.layout.kind,
+        created: Local::now().to_rfc3339(),
+    };
+    let moved = plan.execute(
+        &header,
+        "relayout",
+        &[&source.raw_root, &source.compressed_root],
+        options,
+    );
+
+    println!("\nSummary:");
+    let verb = if options.dry_run {
+        "that would be moved"
+    } else {
+        "moved"
+    };
+    println!("  RAW files {}: {}", verb, moved.raw);
+    println!("  Compressed files {}: {}", verb, moved.compressed);
+    println!("  Sidecars {}: {}", verb, moved.sidecars);
+    println!("  Files already in place: {}", plan.in_place);
+    println!(
+        "  Files left in place because of conflicts: {}",
+        plan.conflicts.len()
+    );
+    println!(
+        "  Other files left in place: {}",
+        total_files.saturating_sub(grouped)
+    );
+    println!("  Errors: {}", errors + moved.errors);
+    plan.print_conflicts();
+}
@@ -0,0 +1,865 @@
+//! The selection phase shared by the `clean` subcommands and `plan`.
+//!
+//! Both trees are scanned, every compressed file is matched against the RAW
+//! index, and the files of the mode are selected, minus protected ones. The
+//! compressed and the RAW direction share the summary, which only differs in
+//! its labels.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    DeleteMode, ScanArgs,
+    matching::{Claims, Matcher, RawIndex, RawMatch, RawStatus},
+    protect::Protection,
+    trash,
+};
+
+/// The outcome of matching the compressed tree against the RAW tree.
+pub struct Selection {
+    /// The files of the side the mode deletes, by RAW status.
+    pub counts: Counts,
+    /// How many paths of the trees could not be read.
+    pub unreadable: usize,
+    /// Whether files with several candidate RAWs were selected, with --allow-ambiguous.
+    pub allow_ambiguous: bool,
+    pub to_delete: Vec<Candidate>,
+}
+
+/// A file selected for deletion.
+pub struct Candidate {
+    pub path: PathBuf,
+    /// The RAW file that matched a compressed file, if any.
+    pub raw: Option<PathBuf>,
+}
+
+impl AsRef<Path> for Candidate {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Files counted by RAW status, in total or per format or shoot.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Counts {
+    pub total: usize,
+    pub matched: usize,
+    /// How many files could not be checked.
+    pub undetermined: usize,
+    /// How many files are, or have, one of several candidates.
+    #[serde(default)]
+    pub ambiguous: usize,
+}
+
+impl Counts {
+    fn count_match(&mut self, raw_match: &RawMatch) {
+        self.total += 1;
+        match raw_match {
+            RawMatch::Matched { .. } => self.matched += 1,
+            RawMatch::NotMatched => {}
+            RawMatch::Ambiguous(_) => self.ambiguous += 1,
+            RawMatch::Unknown(_) => self.undetermined += 1,
+        }
+    }
+
+    fn count_status(&mut self, status: &RawStatus) {
+        self.total += 1;
+        match status {
+            RawStatus::Matched(_) => self.matched += 1,
+            RawStatus::Orphaned => {}
+            RawStatus::Ambiguous => self.ambiguous += 1,
+            RawStatus::Undetermined(_) => self.undetermined += 1,
+        }
+    }
+
+    /// Returns how many files have no match, or `None` when the counts do not
+    /// add up, e.g. in a hand-edited plan.
+    pub fn unmatched(&self) -> Option<usize> {
+        self.total
+            .checked_sub(self.matched)?
+            .checked_sub(self.undetermined)?
+            .checked_sub(self.ambiguous)
+    }
+
+    /// Returns the counts with their labels for `mode`, for the message when
+    /// the deletion limits are exceeded.
+    pub fn summary(&self, mode: DeleteMode) -> Vec<(&'static str, usize)> {
+        let labels = Labels::of(mode);
+        vec![
+            (labels.total, self.total),
+            (labels.matched, self.matched),
+            (labels.unmatched, self.unmatched().unwrap_or(0)),
+            (labels.undetermined, self.undetermined),
+            (labels.ambiguous, self.ambiguous),
+        ]
+    }
+}
+
+/// How the output of a mode names the files it looks at.
+pub struct Labels {
+    total: &'static str,
+    matched: &'static str,
+    unmatched: &'static str,
+    undetermined: &'static str,
+    ambiguous: &'static str,
+    /// The counted files and what they are matched with, for breakdowns.
+    files: &'static str,
+    other: &'static str,
+    /// The headings of the lists of files whose status is in doubt.
+    ambiguous_list: &'static str,
+    undetermined_list: &'static str,
+    nothing_to_delete: &'static str,
+    /// What the run deletes.
+    pub deleting: &'static str,
+}
+
+impl Labels {
+    pub fn of(mode: DeleteMode) -> Labels {
+        let (nothing_to_delete, deleting) = match mode {
+            DeleteMode::Orphaned => (
+                "All compressed files have corresponding RAW files.",
+                "Deleting orphaned compressed files (no RAW).",
+            ),
+            DeleteMode::Matched => (
+                "No compressed files have corresponding RAW files.",
+                "Deleting matched compressed files (has RAW).",
+            ),
+            DeleteMode::RawOrphaned => (
+                "All RAW files have corresponding compressed files.",
+                "Deleting orphaned RAW files (no compressed file).",
+            ),
+            DeleteMode::RawMatched => (
+                "No RAW files have corresponding compressed files.",
+                "Deleting matched RAW files (has compressed file).",
+            ),
+        };
+        match mode {
+            DeleteMode::Orphaned | DeleteMode::Matched => Labels {
+                total: "Total compressed files",
+                matched: "Files with matching RAW",
+                unmatched: "Files without matching RAW",
+                undetermined: "Files with undetermined RAW status",
+                ambiguous: "Files with ambiguous RAW",
+                files: "files",
+                other: "RAW",
+                ambiguous_list: "Several RAWs could belong to these files:",
+                undetermined_list: "Could not determine whether these files have a RAW:",
+                nothing_to_delete,
+                deleting,
+            },
+            DeleteMode::RawOrphaned | DeleteMode::RawMatched => Labels {
+                total: "Total RAW files",
+                matched: "RAW files with matching compressed file",
+                unmatched: "RAW files without matching compressed file",
+                undetermined: "RAW files with undetermined status",
+                ambiguous: "RAW files that are one of several candidates",
+                files: "RAW files",
+                other: "compressed file",
+                ambiguous_list: "These RAW files are one of several candidates of a compressed file:",
+                undetermined_list: "Could not determine whether these RAW files have a compressed file:",
+                nothing_to_delete,
+                deleting,
+            },
+        }
+    }
+}
+
+/// Both trees, scanned and indexed.
+struct Trees {
+    compressed_files: Vec<PathBuf>,
+    raw_index: RawIndex,
+    /// The paths that could not be read, those of the compressed tree first.
+    walk_errors: Vec<walkdir::Error>,
+    /// How many of `walk_errors` are from the compressed tree.
+    compressed_errors: usize,
+    scan_time: Duration,
+    index_time: Duration,
+}
+
+/// Scans the compressed tree and indexes the RAW tree.
+///
+/// With `--abort-on-walk-error`, exits if any part of either tree is unreadable.
+fn scan_trees(scan: &ScanArgs, matcher: &Matcher) -> Trees {
+    let raw_root = scan.roots.raw_root();
+    let compressed_root = scan.roots.compressed_root();
+    println!(
+        "Scanning for compressed files in {}...",
+        compressed_root.display()
+    );
+
+    let started = Instant::now();
+    let (compressed_files, mut walk_errors) = get_compressed_files(compressed_root, matcher);
+    let scan_time = started.elapsed();
+    println!("Found {} compressed files", compressed_files.len());
+    let compressed_errors = walk_errors.len();
+
+    println!("Indexing RAW files in {}...", raw_root.display());
+    let started = Instant::now();
+    let (raw_index, raw_walk_errors) = matcher.index(raw_root);
+    let index_time = started.elapsed();
+    add_walk_errors(&mut walk_errors, raw_walk_errors);
+
+    if scan.abort_on_walk_error && !walk_errors.is_empty() {
+        eprintln!(
+            "\nError: {} paths could not be read, aborting:",
+            walk_errors.len()
+        );
+        print_walk_errors(&walk_errors);
+        process::exit(1);
+    }
+
+    Trees {
+        compressed_files,
+        raw_index,
+        walk_errors,
+        compressed_errors,
+        scan_time,
+        index_time,
+    }
+}
+
+/// Scans both trees, prints the matching summary and selects the files to delete.
+///
+/// The RAW tree is walked once into an index that all lookups are resolved
+/// from. With `--abort-on-walk-error`, the process exits before anything is
+/// selected if any part of either tree is unreadable.
+pub fn select_files(
+    scan: &ScanArgs,
+    matcher: &Matcher,
+    mode: DeleteMode,
+    verbose: bool,
+    summary_only: bool,
+) -> Selection {
+    if matches!(mode, DeleteMode::RawOrphaned | DeleteMode::RawMatched) {
+        return select_raw_files(scan, matcher, mode, verbose, summary_only);
+    }
+    let compressed_root = scan.roots.compressed_root();
+    let trees = scan_trees(scan, matcher);
+
+    let mut to_delete = Vec::new();
+    let mut counts = Counts::default();
+    let mut undetermined = Vec::new();
+    let mut ambiguous = Vec::new();
+    let mut by_format: BTreeMap<String, Counts> = BTreeMap::new();
+    let mut by_shoot: BTreeMap<String, Counts> = BTreeMap::new();
+    let allow_ambiguous = scan.allow_ambiguous && matches!(mode, DeleteMode::Matched);
+    let started = Instant::now();
+
+    for compressed_file in &trees.compressed_files {
+        let raw_match = trees
+            .raw_index
+            .find_matching_raw(compressed_file, compressed_root);
+        counts.count_match(&raw_match);
+        by_format
+            .entry(
+                matcher
+                    .compressed_formats
+                    .format_of(compressed_file)
+                    .unwrap_or_default(),
+            )
+            .or_default()
+            .count_match(&raw_match);
+        if let Some(shoot) = shoot_of(matcher, compressed_file, compressed_root) {
+            by_shoot.entry(shoot).or_default().count_match(&raw_match);
+        }
+
+        match raw_match {
+            RawMatch::Matched { raw: raw_file, via } => {
+                if verbose && !summary_only {
+                    match via {
+                        Some(via) => println!(
+                            "MATCH {} -> {} ({})",
+                            compressed_file.display(),
+                            raw_file.display(),
+                            via
+                        ),
+                        None => println!(
+                            "MATCH {} -> {}",
+                            compressed_file.display(),
+                            raw_file.display()
+                        ),
+                    }
+                }
+                if matches!(mode, DeleteMode::Matched) {
+                    to_delete.push(Candidate {
+                        path: compressed_file.clone(),
+                        raw: Some(raw_file),
+                    });
+                }
+            }
+            RawMatch::NotMatched => {
+                if verbose && !summary_only {
+                    println!("NO_MATCH {}", compressed_file.display());
+                }
+                if matches!(mode, DeleteMode::Orphaned) {
+                    to_delete.push(Candidate {
+                        path: compressed_file.clone(),
+                        raw: None,
+                    });
+                }
+            }
+            RawMatch::Ambiguous(raws) => {
+                if verbose && !summary_only {
+                    println!(
+                        "AMBIGUOUS {} -> {}",
+                        compressed_file.display(),
+                        display_paths(&raws)
+                    );
+                }
+                if allow_ambiguous {
+                    to_delete.push(Candidate {
+                        path: compressed_file.clone(),
+                        raw: Some(raws[0].clone()),
+                    });
+                }
+                ambiguous.push((compressed_file.clone(), raws));
+            }
+            RawMatch::Unknown(e) => {
+                if verbose && !summary_only {
+                    println!("UNKNOWN {} ({})", compressed_file.display(), e);
+                }
+                undetermined.push((compressed_file.clone(), e.to_string()));
+            }
+        }
+    }
+    let match_time = started.elapsed();
+
+    let started = Instant::now();
+    let protected =
+        Protection::new(compressed_root).drop_protected(&mut to_delete, verbose && !summary_only);
+    let protect_time = started.elapsed();
+
+    Summary {
+        mode,
+        counts: &counts,
+        allow_ambiguous,
+        can_be_ambiguous: matcher.can_be_ambiguous(),
+        protected,
+        walk_errors: &trees.walk_errors,
+        breakdowns: vec![("By format", by_format), ("By shoot", by_shoot)],
+        timings: [trees.scan_time, trees.index_time, match_time, protect_time],
+        ambiguous: &ambiguous,
+        undetermined: &undetermined,
+        nothing_selected: to_delete.is_empty(),
+    }
+    .print(summary_only);
+
+    Selection {
+        counts,
+        unreadable: trees.walk_errors.len(),
+        allow_ambiguous,
+        to_delete,
+    }
+}
+
+/// Records which RAWs of `raw_index` the compressed files belong to, or might.
+///
+/// Unreadable parts of the compressed tree make the RAWs their files would
+/// match undetermined.
+pub fn claim_raws(
+    raw_index: &RawIndex,
+    compressed_root: &Path,
+    compressed_files: &[PathBuf],
+    walk_errors: &[walkdir::Error],
+) -> Claims {
+    let mut claims = Claims::default();
+    for compressed_file in compressed_files {
+        raw_index.claim(&mut claims, compressed_file, compressed_root);
+    }
+    for e in walk_errors {
+        let relative = e
+            .path()
+            .and_then(|path| path.strip_prefix(compressed_root).ok())
+            .unwrap_or(Path::new(""));
+        raw_index.claim_unreadable(&mut claims, relative, e.to_string());
+    }
+    claims
+}
+
+/// Scans both trees, prints the summary and selects the RAW files that no
+/// compressed file matches for `clean-raw`, or that one matches for
+/// `clean-raw-matched`.
+///
+/// Every compressed file is matched as in [`select_files`], so RAWs are kept
+/// under exactly the rules that would keep their compressed files. RAWs that
+/// are one of several candidates, or that a compressed file which could not
+/// be checked may belong to, are never selected.
+fn select_raw_files(
+    scan: &ScanArgs,
+    matcher: &Matcher,
+    mode: DeleteMode,
+    verbose: bool,
+    summary_only: bool,
+) -> Selection {
+    let raw_root = scan.roots.raw_root();
+    let compressed_root = scan.roots.compressed_root();
+    let trees = scan_trees(scan, matcher);
+    let raws = trees.raw_index.raws();
+    println!("Found {} RAW files", raws.len());
+
+    let started = Instant::now();
+    let claims = claim_raws(
+        &trees.raw_index,
+        compressed_root,
+        &trees.compressed_files,
+        &trees.walk_errors[..trees.compressed_errors],
+    );
+    let mut to_delete = Vec::new();
+    let mut counts = Counts::default();
+    let mut undetermined = Vec::new();
+    let mut ambiguous = Vec::new();
+    let mut by_shoot: BTreeMap<String, Counts> = BTreeMap::new();
+    for raw in &raws {
+        let status = claims.status(raw);
+        counts.count_status(&status);
+        if let Some(shoot) = shoot_of(matcher, raw, raw_root) {
+            by_shoot.entry(shoot).or_default().count_status(&status);
+        }
+        match status {
+            RawStatus::Matched(compressed_file) => {
+                if verbose && !summary_only {
+                    println!("MATCH {} <- {}", raw.display(), compressed_file.display());
+                }
+                if matches!(mode, DeleteMode::RawMatched) {
+                    to_delete.push(Candidate {
+                        path: raw.to_path_buf(),
+                        raw: None,
+                    });
+                }
+            }
+            RawStatus::Orphaned => {
+                if verbose && !summary_only {
+                    println!("NO_MATCH {}", raw.display());
+                }
+                if matches!(mode, DeleteMode::RawOrphaned) {
+                    to_delete.push(Candidate {
+                        path: raw.to_path_buf(),
+                        raw: None,
+                    });
+                }
+            }
+            RawStatus::Ambiguous => {
+                if verbose && !summary_only {
+                    println!("AMBIGUOUS {}", raw.display());
+                }
+                ambiguous.push((raw.to_path_buf(), Vec::new()));
+            }
+            RawStatus::Undetermined(reason) => {
+                if verbose && !summary_only {
+                    println!("UNKNOWN {} ({})", raw.display(), reason);
+                }
+                undetermined.push((raw.to_path_buf(), reason));
+            }
+        }
+    }
+    let match_time = started.elapsed();
+
+    let started = Instant::now();
+    let protected =
+        Protection::new(raw_root).drop_protected(&mut to_delete, verbose && !summary_only);
+    let protect_time = started.elapsed();
+
+    Summary {
+        mode,
+        counts: &counts,
+        allow_ambiguous: false,
+        can_be_ambiguous: matcher.can_be_ambiguous(),
+        protected,
+        walk_errors: &trees.walk_errors,
+        breakdowns: vec![("By shoot", by_shoot)],
+        timings: [trees.scan_time, trees.index_time, match_time, protect_time],
+        ambiguous: &ambiguous,
+        undetermined: &undetermined,
+        nothing_selected: to_delete.is_empty(),
+    }
+    .print(summary_only);
+
+    Selection {
+        counts,
+        unreadable: trees.walk_errors.len(),
+        allow_ambiguous: false,
+        to_delete,
+    }
+}
+
+/// What a selection found, for the summary.
+struct Summary<'a> {
+    mode: DeleteMode,
+    counts: &'a Counts,
+    /// Whether --allow-ambiguous selected the ambiguous files.
+    allow_ambiguous: bool,
+    can_be_ambiguous: bool,
+    protected: usize,
+    walk_errors: &'a [walkdir::Error],
+    /// Breakdowns by format or shoot, with their titles.
+    breakdowns: Vec<(&'static str, BTreeMap<String, Counts>)>,
+    /// How long scanning, indexing, matching and protection took.
+    timings: [Duration; 4],
+    /// The files with several candidate RAWs, with the candidates if known.
+    ambiguous: &'a [(PathBuf, Vec<PathBuf>)],
+    /// The files that could not be checked, with the reason.
+    undetermined: &'a [(PathBuf, String)],
+    nothing_selected: bool,
+}
+
+impl Summary<'_> {
+    /// Prints the counts, and unless `summary_only` the files in doubt.
+    fn print(&self, summary_only: bool) {
+        let labels = Labels::of(self.mode);
+        let counts = self.counts;
+        println!("\nSummary:");
+        println!("  {}: {}", labels.total, counts.total);
+        println!("  {}: {}", labels.matched, counts.matched);
+        println!(
+            "  {}: {}",
+            labels.unmatched,
+            counts.unmatched().unwrap_or(0)
+        );
+        println!(
+            "  {} (never deleted): {}",
+            labels.undetermined, counts.undetermined
+        );
+        if self.can_be_ambiguous {
+            let note = if self.allow_ambiguous {
+                "deleted because of --allow-ambiguous"
+            } else {
+                "never deleted"
+            };
+            println!("  {} ({}): {}", labels.ambiguous, note, counts.ambiguous);
+        }
+        println!("  Protected files (never deleted): {}", self.protected);
+        println!("  Unreadable paths: {}", self.walk_errors.len());
+        for (title, groups) in &self.breakdowns {
+            if !groups.is_empty() {
+                print_breakdown(title, groups, &labels, self.can_be_ambiguous);
+            }
+        }
+        let [scan_time, index_time, match_time, protect_time] = self.timings;
+        println!(
+            "  Timings: scan {:.2?}, RAW index {:.2?}, matching {:.2?}, protection {:.2?}",
+            scan_time, index_time, match_time, protect_time
+        );
+        println!("  {}", labels.deleting);
+
+        if !self.walk_errors.is_empty() {
+            eprintln!("\nWarning: The scan is incomplete, these paths could not be read:");
+            print_walk_errors(self.walk_errors);
+        }
+
+        if !self.ambiguous.is_empty() && !summary_only {
+            println!("\n{}", labels.ambiguous_list);
+            for (file, raws) in self.ambiguous {
+                if raws.is_empty() {
+                    println!("  {}", file.display());
+                } else {
+                    println!("  {} -> {}", file.display(), display_paths(raws));
+                }
+            }
+        }
+
+        if !self.undetermined.is_empty() && !summary_only {
+            println!("\n{}", labels.undetermined_list);
+            for (file, reason) in self.undetermined {
+                println!("  {} ({})", file.display(), reason);
+            }
+        }
+
+        if self.nothing_selected {
+            if self.protected > 0 {
+                println!(
+                    "\nNo files to delete. All {} {} that would have been deleted are protected.",
+                    self.protected, labels.files
+                );
+            } else {
+                println!("\nNo files to delete. {}", labels.nothing_to_delete);
+            }
+            if !self.walk_errors.is_empty() {
+                println!("Only the readable parts of the directories were checked.");
+            }
+        }
+    }
+}
+
+/// Prints one summary line per group of a breakdown, e.g. per format.
+fn print_breakdown<K: Display>(
+    title: &str,
+    groups: &BTreeMap<K, Counts>,
+    labels: &Labels,
+    can_be_ambiguous: bool,
+) {
+    println!("  {}:", title);
+    for (group, counts) in groups {
+        let ambiguous = if can_be_ambiguous {
+            format!(", {} ambiguous", counts.ambiguous)
+        } else {
+            String::new()
+        };
+        println!(
+            "    {}: {} {}, {} with matching {}, {} without, {} undetermined{}",
+            group,
+            counts.total,
+            labels.files,
+            counts.matched,
+            labels.other,
+            counts.unmatched().unwrap_or(0),
+            counts.undetermined,
+            ambiguous
+        );
+    }
+}
+
+/// Returns the compressed files below `compressed_root` and the paths that could not be read.
+pub fn get_compressed_files(
+    compressed_root: &Path,
+    matcher: &Matcher,
+) -> (Vec<PathBuf>, Vec<walkdir::Error>) {
+    let mut compressed_files = Vec::new();
+    let mut walk_errors = Vec::new();
+
+    for entry in trash::skip_trash(WalkDir::new(compressed_root)) {
+        match entry {
+            Ok(entry) => {
+                let path = entry.path();
+                if entry.file_type().is_file()
+                    && matcher.compressed_formats.is_compressed(path)
+                    && path
+                        .strip_prefix(compressed_root)
+                        .is_ok_and(|relative| matcher.layout.includes_compressed(relative))
+                {
+                    compressed_files.push(path.to_path_buf());
+                }
+            }
+            Err(e) => walk_errors.push(e),
+        }
+    }
+
+    (compressed_files, walk_errors)
+}
+
+/// Returns the name of the shoot folder of `file` below `root` in the sibling layout.
+fn shoot_of(matcher: &Matcher, file: &Path, root: &Path) -> Option<String> {
+    let shoot = matcher.layout.shoot(file.strip_prefix(root).ok()?)?;
+    if shoot.as_os_str().is_empty() {
+        Some(".".to_string())
+    } else {
+        Some(shoot.display().to_string())
+    }
+}
+
+pub fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn print_walk_errors(walk_errors: &[walkdir::Error]) {
+    // The error messages already name the path that failed.
+    for e in walk_errors {
+        eprintln!("  {}", e);
+    }
+}
+
+/// Adds the errors of walking the RAW tree to those of the compressed tree.
+pub fn add_walk_errors(
+    walk_errors: &mut Vec<walkdir::Error>,
+    raw_walk_errors: Vec<walkdir::Error>,
+) {
+    // A single-root library is walked twice, and its errors are reported once.
+    for e in raw_walk_errors {
+        if !walk_errors
+            .iter()
+            .any(|seen| seen.to_string() == e.to_string())
+        {
+            walk_errors.push(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use clap::Parser;
+
+    use super::*;
+
+    fn touch(root: &Path, files: &[&str]) {
+        for file in files {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), "").unwrap();
+        }
+    }
+
+    fn scan_args(raw_root: &Path, compressed_root: &Path, flags: &[&str]) -> ScanArgs {
+        let roots = [
+            "-r",
+            raw_root.to_str().unwrap(),
+            "-c",
+            compressed_root.to_str().unwrap(),
+        ];
+        ScanArgs::parse_from(["photo-cleanup"].iter().chain(&roots).chain(flags))
+    }
+
+    fn paths(selection: &Selection) -> Vec<&Path> {
+        selection
+            .to_delete
+            .iter()
+            .map(|candidate| candidate.path.as_path())
+            .collect()
+    }
+
+    #[test]
+    fn unreadable_raws_keep_their_compressed_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(&raw_root, &["a/A.RAF"]);
+        touch(&compressed_root, &["a/A.jpg", "a/O.jpg", "b/B.jpg"]);
+        fs::create_dir(raw_root.join("b")).unwrap();
+        symlink(tmp.path().join("missing"), raw_root.join("b/B.RAF")).unwrap();
+        let scan = scan_args(&raw_root, &compressed_root, &[]);
+        let matcher = Matcher::default();
+
+        let selection = select_files(&scan, &matcher, DeleteMode::Orphaned, false, true);
+        assert_eq!(paths(&selection), [compressed_root.join("a/O.jpg")]);
+        assert_eq!(
+            (
+                selection.counts.total,
+                selection.counts.matched,
+                selection.counts.undetermined
+            ),
+            (3, 1, 1)
+        );
+        assert_eq!(selection.unreadable, 1);
+
+        let selection = select_files(&scan, &matcher, DeleteMode::Matched, false, true);
+        assert_eq!(paths(&selection), [compressed_root.join("a/A.jpg")]);
+        assert_eq!(
+            selection.to_delete[0].raw.as_deref(),
+            Some(raw_root.join("a/A.RAF").as_path())
+        );
+    }
+
+    #[test]
+    fn ambiguous_files_are_only_deleted_when_allowed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(&raw_root, &["x/A.RAF", "x/B.RAF", "y/B.RAF"]);
+        touch(&compressed_root, &["a/A.jpg", "a/B.jpg", "a/O.jpg"]);
+        let matcher = Matcher {
+            match_anywhere: true,
+            ..Matcher::default()
+        };
+
+        let scan = scan_args(&raw_root, &compressed_root, &["--match-anywhere"]);
+        let selection = select_files(&scan, &matcher, DeleteMode::Matched, false, true);
+        assert_eq!(paths(&selection), [compressed_root.join("a/A.jpg")]);
+        assert_eq!(selection.counts.ambiguous, 1);
+        let selection = select_files(&scan, &matcher, DeleteMode::Orphaned, false, true);
+        assert_eq!(paths(&selection), [compressed_root.join("a/O.jpg")]);
+
+        let scan = scan_args(
+            &raw_root,
+            &compressed_root,
+            &["--match-anywhere", "--allow-ambiguous"],
+        );
+        let selection = select_files(&scan, &matcher, DeleteMode::Matched, false, true);
+        assert!(selection.allow_ambiguous);
+        assert_eq!(
+            paths(&selection),
+            [
+                compressed_root.join("a/A.jpg"),
+                compressed_root.join("a/B.jpg")
+            ]
+        );
+        // Ambiguous files do have a RAW, so `clean` never deletes them.
+        let selection = select_files(&scan, &matcher, DeleteMode::Orphaned, false, true);
+        assert!(!selection.allow_ambiguous);
+        assert_eq!(paths(&selection), [compressed_root.join("a/O.jpg")]);
+    }
+
+    #[test]
+    fn claims_keep_the_raws_compressed_files_may_belong_to() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(
+            &raw_root,
+            &["a/A.RAF", "a/O.RAF", "b/B.RAF", "x/C.RAF", "y/C.RAF"],
+        );
+        touch(&compressed_root, &["a/A.jpg", "a/C.jpg", "b/B.jpg"]);
+        let compressed_files = [
+            compressed_root.join("a/A.jpg"),
+            compressed_root.join("a/C.jpg"),
+        ];
+        // An entry of b/ that could not be read, e.g. one that vanished during the walk.
+        let walk_error = WalkDir::new(compressed_root.join("b/gone"))
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        let walk_errors = [walk_error];
+
+        let (index, _) = Matcher::default().index(&raw_root);
+        let claims = claim_raws(&index, &compressed_root, &compressed_files, &walk_errors);
+        let status = |raw: &str| claims.status(&raw_root.join(raw));
+        assert!(
+            matches!(status("a/A.RAF"), RawStatus::Matched(file) if file == compressed_root.join("a/A.jpg"))
+        );
+        assert!(matches!(status("a/O.RAF"), RawStatus::Orphaned));
+        assert!(matches!(status("b/B.RAF"), RawStatus::Undetermined(_)));
+        assert!(matches!(status("x/C.RAF"), RawStatus::Orphaned));
+
+        // With match_anywhere, C.jpg has two candidates, and the unreadable
+        // path could hold the file of any RAW.
+        let matcher = Matcher {
+            match_anywhere: true,
+            ..Matcher::default()
+        };
+        let (index, _) = matcher.index(&raw_root);
+        let claims = claim_raws(&index, &compressed_root, &compressed_files, &[]);
+        let status = |raw: &str| claims.status(&raw_root.join(raw));
+        assert!(matches!(status("x/C.RAF"), RawStatus::Ambiguous));
+        assert!(matches!(status("y/C.RAF"), RawStatus::Ambiguous));
+        assert!(matches!(status("b/B.RAF"), RawStatus::Orphaned));
+        let claims = claim_raws(&index, &compressed_root, &compressed_files, &walk_errors);
+        assert!(matches!(
+            claims.status(&raw_root.join("a/O.RAF")),
+            RawStatus::Undetermined(_)
+        ));
+    }
+
+    #[test]
+    fn raw_selection_keeps_ambiguous_raws() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(&raw_root, &["a/A.RAF", "a/O.RAF", "x/C.RAF", "y/C.RAF"]);
+        touch(&compressed_root, &["a/A.jpg", "a/C.jpg"]);
+        let matcher = Matcher {
+            match_anywhere: true,
+            ..Matcher::default()
+        };
+        let scan = scan_args(&raw_root, &compressed_root, &["--match-anywhere"]);
+
+        let selection = select_files(&scan, &matcher, DeleteMode::RawOrphaned, false, true);
+        assert_eq!(paths(&selection), [raw_root.join("a/O.RAF")]);
+        assert_eq!(
+            (
+                selection.counts.total,
+                selection.counts.matched,
+                selection.counts.ambiguous
+            ),
+            (4, 1, 2)
+        );
+        let selection = select_files(&scan, &matcher, DeleteMode::RawMatched, false, true);
+        assert_eq!(paths(&selection), [raw_root.join("a/A.RAF")]);
+    }
+}
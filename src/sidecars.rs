@@ -0,0 +1,601 @@
+//! Sidecars, the files editors keep next to an image with its edits.
+//!
+//! A sidecar is named either after the whole name of its image,
+//! `DSCF1234.RAF.xmp`, or after its stem, `DSCF1234.xmp`, depending on the
+//! editor. The stem form is shared by all images with that stem in the
+//! directory, so it only goes with the last of them. darktable names the XMPs
+//! of duplicates `DSCF1234_01.RAF.xmp`. Some editors keep their sidecars in a
+//! subdirectory, e.g. Capture One in `CaptureOne/Settings153/`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde::Deserialize;
+use walkdir::WalkDir;
+
+use crate::{matching::Matcher, trash};
+
+/// The built-in conventions: extension, editor, naming and subdirectory.
+pub const BUILTIN: &[(&str, &str, Naming, Option<&str>)] = &[
+    ("xmp", "XMP", Naming::Both, None),
+    ("pp3", "RawTherapee", Naming::Name, None),
+    ("dop", "DxO PhotoLab", Naming::Name, None),
+    ("on1", "ON1 Photo RAW", Naming::Stem, None),
+    (
+        "cos",
+        "Capture One",
+        Naming::Name,
+        Some("CaptureOne/Settings*"),
+    ),
+];
+
+/// What a sidecar is named after.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    /// The whole image name, `DSCF1234.RAF.pp3`.
+    Name,
+    /// The image stem, `DSCF1234.on1`.
+    Stem,
+    /// Either.
+    #[default]
+    Both,
+}
+
+/// A sidecar convention as written in the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConventionSpec {
+    pub extension: String,
+    #[serde(default)]
+    pub naming: Naming,
+    /// The subdirectory of the image directory the sidecars are in, with `*`
+    /// matching any characters of a component.
+    #[serde(default)]
+    pub directory: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Convention {
+    extension: String,
+    naming: Naming,
+    /// The patterns of the subdirectory components, if any.
+    directory: Vec<Regex>,
+    /// Whether sidecars of duplicates, `DSCF1234_01.RAF.xmp`, belong to the image too.
+    duplicates: bool,
+}
+
+impl Convention {
+    fn new(spec: &ConventionSpec) -> Result<Convention, String> {
+        let extension = spec.extension.trim_start_matches('.').to_ascii_lowercase();
+        if extension.is_empty() || extension.contains(['.', '/']) {
+            return Err(format!("invalid sidecar extension `{}`", spec.extension));
+        }
+        let mut directory = Vec::new();
+        for component in spec.directory.iter().flat_map(|dir| dir.split('/')) {
+            if component.is_empty() || component == "." || component == ".." {
+                return Err(format!(
+                    "invalid sidecar directory `{}`",
+                    spec.directory.as_deref().unwrap_or_default()
+                ));
+            }
+            let pattern = regex::escape(component).replace(r"\*", ".*");
+            directory.push(Regex::new(&format!("^{pattern}$")).map_err(|e| e.to_string())?);
+        }
+        Ok(Convention {
+            // darktable keeps one XMP per duplicate of an image.
+            duplicates: extension == "xmp",
+            extension,
+            naming: spec.naming,
+            directory,
+        })
+    }
+
+    fn has_extension(&self, name: &OsStr) -> bool {
+        Path::new(name)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(&self.extension))
+    }
+
+    /// Returns whether a sidecar with the stem `sidecar_stem` belongs to the
+    /// image `name` with the stem `stem`, counting the stem form only if `by_stem`.
+    fn belongs_to(&self, sidecar_stem: &OsStr, name: &OsStr, stem: &OsStr, by_stem: bool) -> bool {
+        let by_name = is_named(sidecar_stem, name)
+            || (self.duplicates
+                && duplicate_of(sidecar_stem).is_some_and(|original| is_named(&original, name)));
+        let by_stem = by_stem && sidecar_stem == stem;
+        match self.naming {
+            Naming::Name => by_name,
+            Naming::Stem => by_stem,
+            Naming::Both => by_name || by_stem,
+        }
+    }
+
+    /// Returns the image directory of a sidecar in `dir`, or `None` if the
+    /// convention does not put sidecars there.
+    fn image_dir(&self, dir: &Path) -> Option<PathBuf> {
+        let mut image_dir = dir;
+        for pattern in self.directory.iter().rev() {
+            let name = image_dir.file_name()?.to_str()?;
+            if !pattern.is_match(name) {
+                return None;
+            }
+            image_dir = image_dir.parent()?;
+        }
+        Some(image_dir.to_path_buf())
+    }
+}
+
+/// The sidecar conventions the cleaner knows, the built-in ones first.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    conventions: Vec<Convention>,
+}
+
+impl Registry {
+    /// Builds the registry from the built-in conventions and `specs` from the config file.
+    pub fn new(specs: &[ConventionSpec]) -> Result<Registry, String> {
+        let builtin = BUILTIN
+            .iter()
+            .map(|(extension, _, naming, directory)| ConventionSpec {
+                extension: extension.to_string(),
+                naming: *naming,
+                directory: directory.map(str::to_string),
+            });
+        let conventions = builtin
+            .chain(specs.iter().cloned())
+            .map(|spec| Convention::new(&spec))
+            .collect::<Result<_, _>>()?;
+        Ok(Registry { conventions })
+    }
+
+    /// Returns whether the file `name` is a sidecar of any convention.
+    pub fn is_sidecar(&self, name: &OsStr) -> bool {
+        self.conventions.iter().any(|c| c.has_extension(name))
+    }
+}
+
+/// Returns whether the file name `entry` is `name`, ignoring the case of the extension.
+fn is_named(entry: &OsStr, name: &OsStr) -> bool {
+    let (entry, name) = (Path::new(entry), Path::new(name));
+    entry == name
+        || (entry.file_stem() == name.file_stem()
+            && entry
+                .extension()
+                .zip(name.extension())
+                .is_some_and(|(a, b)| a.eq_ignore_ascii_case(b)))
+}
+
+/// Returns the name a darktable duplicate name like `DSCF1234_01.RAF` stands
+/// for, `DSCF1234.RAF`.
+fn duplicate_of(name: &OsStr) -> Option<OsString> {
+    let name = Path::new(name);
+    let stem = name.file_stem()?.to_str()?;
+    let extension = name.extension()?.to_str()?;
+    let (original, number) = stem.rsplit_once('_')?;
+    if original.is_empty() || number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{original}.{extension}").into())
+}
+
+/// A directory entry, with whether it is a directory.
+struct Entry {
+    name: OsString,
+    is_dir: bool,
+}
+
+/// Finds the sidecars that go with images as they are removed.
+///
+/// Directory listings are read once and then kept up to date with the
+/// removals, so dry runs see the same groups as real runs.
+pub struct Sidecars<'a> {
+    registry: &'a Registry,
+    listings: HashMap<PathBuf, Vec<Entry>>,
+}
+
+impl<'a> Sidecars<'a> {
+    pub fn new(registry: &'a Registry) -> Sidecars<'a> {
+        Sidecars {
+            registry,
+            listings: HashMap::new(),
+        }
+    }
+
+    /// Records `image` and its sidecars as removed and returns the sidecars.
+    ///
+    /// Sidecars named after the whole image name always go with it. Sidecars
+    /// named after the stem only go when no other image with that stem is left.
+    pub fn take(&mut self, image: &Path) -> io::Result<Vec<PathBuf>> {
+        let (Some(dir), Some(name), Some(stem)) =
+            (image.parent(), image.file_name(), image.file_stem())
+        else {
+            return Ok(Vec::new());
+        };
+        let registry = self.registry;
+        let listing = self.listing(dir)?;
+        listing.retain(|entry| entry.is_dir || !is_named(&entry.name, name));
+        let images: Vec<OsString> = listing
+            .iter()
+            .filter(|entry| !entry.is_dir && !registry.is_sidecar(&entry.name))
+            .map(|entry| entry.name.clone())
+            .collect();
+        let stem_shared = images
+            .iter()
+            .any(|other| Path::new(other).file_stem() == Some(stem));
+
+        let mut sidecars = Vec::new();
+        for convention in &registry.conventions {
+            for sidecar_dir in self.resolve(dir, &convention.directory)? {
+                self.listing(&sidecar_dir)?.retain(|entry| {
+                    let goes = !entry.is_dir
+                        && convention.has_extension(&entry.name)
+                        && Path::new(&entry.name)
+                            .file_stem()
+                            .is_some_and(|sidecar_stem| {
+                                convention.belongs_to(sidecar_stem, name, stem, !stem_shared)
+                                    // A sidecar named after another image that
+                                    // is left, e.g. `A_01.RAF`, stays with it.
+                                    && !images.iter().any(|other| is_named(sidecar_stem, other))
+                            });
+                    if goes {
+                        sidecars.push(sidecar_dir.join(&entry.name));
+                    }
+                    !goes
+                });
+            }
+        }
+        sidecars.sort();
+        Ok(sidecars)
+    }
+
+    /// Returns the listing of `dir`, reading it on first use.
+    fn listing(&mut self, dir: &Path) -> io::Result<&mut Vec<Entry>> {
+        if !self.listings.contains_key(dir) {
+            let mut listing = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                listing.push(Entry {
+                    name: entry.file_name(),
+                    is_dir: entry.file_type()?.is_dir(),
+                });
+            }
+            self.listings.insert(dir.to_path_buf(), listing);
+        }
+        Ok(self.listings.get_mut(dir).expect("inserted above"))
+    }
+
+    /// Returns the existing subdirectories of `dir` that match `patterns`.
+    fn resolve(&mut self, dir: &Path, patterns: &[Regex]) -> io::Result<Vec<PathBuf>> {
+        let mut dirs = vec![dir.to_path_buf()];
+        for pattern in patterns {
+            let mut matched = Vec::new();
+            for parent in dirs {
+                for entry in self.listing(&parent)? {
+                    if entry.is_dir && entry.name.to_str().is_some_and(|n| pattern.is_match(n)) {
+                        matched.push(parent.join(&entry.name));
+                    }
+                }
+            }
+            dirs = matched;
+        }
+        Ok(dirs)
+    }
+}
+
+/// The sidecars whose image exists in neither tree.
+pub struct Orphans {
+    pub compressed: Vec<PathBuf>,
+    pub raw: Vec<PathBuf>,
+    pub walk_errors: Vec<walkdir::Error>,
+}
+
+/// Finds the sidecars in both trees whose image exists in neither.
+///
+/// The image of a sidecar is looked for in its image directory and in the
+/// directory that corresponds to it in the other tree, by exact name. Paths
+/// in `removed` count as already gone and are not reported.
+pub fn find_orphans(
+    matcher: &Matcher,
+    registry: &Registry,
+    raw_root: &Path,
+    compressed_root: &Path,
+    removed: &HashSet<PathBuf>,
+) -> Orphans {
+    let mut walk_errors = Vec::new();
+    let compressed = list_tree(compressed_root, false, removed, &mut walk_errors);
+    // A single-root library is one tree, listed and checked once.
+    let single_root = raw_root == compressed_root;
+    let raw = if single_root {
+        compressed.clone()
+    } else {
+        list_tree(raw_root, true, removed, &mut walk_errors)
+    };
+
+    // Mapping rules only work in one direction, so RAW directories are traced
+    // back to all compressed directories that map to them. Directories that
+    // are no compressed folder of the sibling layout stand for themselves.
+    let to_raw = |dir: &Path| {
+        let mapped = matcher.mapping.map(dir).ok()?.dir;
+        let exists = |raw_dir: &Path| raw_root.join(raw_dir).is_dir();
+        Some(matcher.layout.raw_dir(&mapped, exists).unwrap_or(mapped))
+    };
+    let mut to_compressed: HashMap<PathBuf, Vec<&Path>> = HashMap::new();
+    for dir in compressed.keys() {
+        if let Some(mapped) = to_raw(dir) {
+            to_compressed.entry(mapped).or_default().push(dir);
+        }
+    }
+
+    let mut orphans = Orphans {
+        compressed: Vec::new(),
+        raw: Vec::new(),
+        walk_errors,
+    };
+    for (dir, names) in &compressed {
+        for name in names {
+            let is_orphan = is_orphan(registry, dir, name, |image_dir| {
+                // A directory the rules map outside the RAW root is left alone.
+                let raw_dir = to_raw(image_dir)?;
+                // In a single root, RAW directories are checked in this loop too.
+                let paired = to_compressed
+                    .get(image_dir)
+                    .into_iter()
+                    .flatten()
+                    .filter(|_| single_root)
+                    .filter_map(|compressed_dir| compressed.get(*compressed_dir));
+                Some(
+                    [compressed.get(image_dir), raw.get(&raw_dir)]
+                        .into_iter()
+                        .flatten()
+                        .chain(paired)
+                        .collect(),
+                )
+            });
+            if is_orphan {
+                orphans
+                    .compressed
+                    .push(compressed_root.join(dir).join(name));
+            }
+        }
+    }
+    for (dir, names) in raw.iter().filter(|_| !single_root) {
+        for name in names {
+            let is_orphan = is_orphan(registry, dir, name, |image_dir| {
+                let others = to_compressed
+                    .get(image_dir)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|compressed_dir| compressed.get(*compressed_dir));
+                Some(raw.get(image_dir).into_iter().chain(others).collect())
+            });
+            if is_orphan {
+                orphans.raw.push(raw_root.join(dir).join(name));
+            }
+        }
+    }
+    orphans.compressed.sort();
+    orphans.raw.sort();
+    orphans
+}
+
+/// Returns the file names below `root`, by relative directory.
+fn list_tree(
+    root: &Path,
+    follow_links: bool,
+    removed: &HashSet<PathBuf>,
+    walk_errors: &mut Vec<walkdir::Error>,
+) -> HashMap<PathBuf, Vec<OsString>> {
+    let mut listings: HashMap<PathBuf, Vec<OsString>> = HashMap::new();
+    for entry in trash::skip_trash(WalkDir::new(root).follow_links(follow_links)) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                walk_errors.push(e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        if !entry.file_type().is_file() || removed.contains(path) {
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        listings
+            .entry(relative.parent().unwrap_or(Path::new("")).to_path_buf())
+            .or_default()
+            .push(entry.file_name().to_os_string());
+    }
+    listings
+}
+
+/// Returns whether `name` in `dir` is a sidecar whose image is gone.
+///
+/// `listings` returns the file names of both trees that the image could be
+/// among for an image directory, or `None` when that cannot be told.
+fn is_orphan<'a>(
+    registry: &Registry,
+    dir: &Path,
+    name: &OsStr,
+    listings: impl Fn(&Path) -> Option<Vec<&'a Vec<OsString>>>,
+) -> bool {
+    let Some(target) = Path::new(name).file_stem() else {
+        return false;
+    };
+    let mut applies = false;
+    for convention in &registry.conventions {
+        if !convention.has_extension(name) {
+            continue;
+        }
+        let Some(image_dir) = convention.image_dir(dir) else {
+            continue;
+        };
+        let Some(listings) = listings(&image_dir) else {
+            return false;
+        };
+        applies = true;
+        let image_exists = listings.into_iter().flatten().any(|entry| {
+            !registry.is_sidecar(entry)
+                && Path::new(entry).file_stem().is_some_and(|entry_stem| {
+                    convention.belongs_to(target, entry, entry_stem, true)
+                })
+        });
+        if image_exists {
+            return false;
+        }
+    }
+    applies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(root: &Path, files: &[&str]) {
+        for file in files {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), "").unwrap();
+        }
+    }
+
+    #[test]
+    fn stem_sidecars_go_with_the_last_image_of_the_stem() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        touch(dir, &["A.RAF", "A.JPG", "A.xmp", "A.JPG.xmp", "A.RAF.xmp"]);
+        let registry = Registry::new(&[]).unwrap();
+        let mut sidecars = Sidecars::new(&registry);
+
+        assert_eq!(
+            sidecars.take(&dir.join("A.JPG")).unwrap(),
+            [dir.join("A.JPG.xmp")]
+        );
+        assert_eq!(
+            sidecars.take(&dir.join("A.RAF")).unwrap(),
+            [dir.join("A.RAF.xmp"), dir.join("A.xmp")]
+        );
+    }
+
+    #[test]
+    fn darktable_duplicates_go_with_their_image() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        touch(
+            dir,
+            &[
+                "A.RAF",
+                "A.RAF.xmp",
+                "A_01.RAF.xmp",
+                "A_02.raf.xmp",
+                "A_1.RAF",
+                "A_1.RAF.xmp",
+                "B_01.RAF.xmp",
+            ],
+        );
+        let registry = Registry::new(&[]).unwrap();
+        let mut sidecars = Sidecars::new(&registry);
+
+        // `A_1.RAF.xmp` belongs to the image `A_1.RAF`, which is left.
+        assert_eq!(
+            sidecars.take(&dir.join("A.RAF")).unwrap(),
+            [
+                dir.join("A.RAF.xmp"),
+                dir.join("A_01.RAF.xmp"),
+                dir.join("A_02.raf.xmp")
+            ]
+        );
+        assert_eq!(
+            sidecars.take(&dir.join("A_1.RAF")).unwrap(),
+            [dir.join("A_1.RAF.xmp")]
+        );
+    }
+
+    #[test]
+    fn capture_one_sidecars_are_found_in_their_settings_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        touch(
+            dir,
+            &[
+                "A.RAF",
+                "CaptureOne/Settings153/A.RAF.cos",
+                "CaptureOne/Settings153/B.RAF.cos",
+                "CaptureOne/Cache/A.RAF.cos",
+                "A.RAF.cos",
+            ],
+        );
+        let registry = Registry::new(&[]).unwrap();
+        let mut sidecars = Sidecars::new(&registry);
+
+        assert_eq!(
+            sidecars.take(&dir.join("A.RAF")).unwrap(),
+            [dir.join("CaptureOne/Settings153/A.RAF.cos")]
+        );
+    }
+
+    #[test]
+    fn orphans_are_found_across_both_trees() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (raw_root, compressed_root) = (tmp.path().join("raw"), tmp.path().join("jpg"));
+        touch(
+            &raw_root,
+            &[
+                "a/A.RAF",
+                "a/A.RAF.xmp",
+                "a/B.RAF.xmp",
+                "a/C.xmp",
+                "a/E.RAF",
+            ],
+        );
+        touch(
+            &compressed_root,
+            &[
+                "a/C.jpg",
+                "a/C.jpg.xmp",
+                "a/D.jpg",
+                "a/D.jpg.xmp",
+                "a/E.xmp",
+                "a/CaptureOne/Settings1/E.RAF.cos",
+                "a/CaptureOne/Settings1/F.RAF.cos",
+            ],
+        );
+        let registry = Registry::new(&[]).unwrap();
+        let matcher = Matcher::default();
+
+        let orphans = find_orphans(
+            &matcher,
+            &registry,
+            &raw_root,
+            &compressed_root,
+            &HashSet::new(),
+        );
+        assert_eq!(orphans.raw, [raw_root.join("a/B.RAF.xmp")]);
+        assert_eq!(
+            orphans.compressed,
+            [compressed_root.join("a/CaptureOne/Settings1/F.RAF.cos")]
+        );
+        assert!(orphans.walk_errors.is_empty());
+
+        // Once C.jpg is deleted in the same run, the stem XMP in the RAW tree
+        // has no image left, and the deleted sidecar is not reported again.
+        let removed = HashSet::from([
+            compressed_root.join("a/C.jpg"),
+            compressed_root.join("a/C.jpg.xmp"),
+        ]);
+        let orphans = find_orphans(&matcher, &registry, &raw_root, &compressed_root, &removed);
+        assert_eq!(
+            orphans.raw,
+            [raw_root.join("a/B.RAF.xmp"), raw_root.join("a/C.xmp")]
+        );
+        assert_eq!(
+            orphans.compressed,
+            [compressed_root.join("a/CaptureOne/Settings1/F.RAF.cos")]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Normalization of compressed file stems before the RAW lookup.
+//!
+//! Editors add suffixes to exported or duplicated images, e.g.
+//! `DSCF1234-Edit.jpg` or `DSCF1234 (2).jpg`. A rule is a regex for such a
+//! suffix; the stem is first looked up as is, then with matching suffixes
+//! stripped one after another, so `DSCF1234-Edit (2)` is tried as
+//! `DSCF1234-Edit` and then as `DSCF1234`.
+//!
+//! Stems can also be compared under a Unicode normalization form, because
+//! macOS volumes store accents decomposed (`e` + U+0301) where Linux tools
+//! usually write them composed (`é`).
+
+use std::ffi::{OsStr, OsString};
+
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{UnicodeNormalization, is_nfc, is_nfd};
+
+/// Suffix patterns of common editors, by preset name.
+pub const PRESETS: &[(&str, &[&str])] = &[
+    (
+        "lightroom",
+        &[
+            r"-Edit(-\d+)?",
+            r"-Enhanced(-NR|-SR)?(-\d+)?",
+            r"-HDR(-\d+)?",
+            r"-Pano(-\d+)?",
+        ],
+    ),
+    ("darktable", &[r"_\d{2}"]),
+    ("capture-one", &[r"_\d+"]),
+    ("copies", &[r" \(\d+\)", r" copy( \d+)?", r"-\d+"]),
+];
+
+/// How many suffixes are stripped from one stem at most.
+const MAX_STRIPS: usize = 8;
+
+/// A Unicode normalization form stems are compared under.
+///
+/// Both forms match the same names; the form decides what stem patterns see.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Composed, e.g. `é` as a single character.
+    Nfc,
+    /// Decomposed, e.g. `é` as `e` and a combining accent.
+    Nfd,
+}
+
+impl Normalization {
+    /// Returns `stem` in this form, or `None` if it already is or is not UTF-8.
+    pub fn apply(self, stem: &OsStr) -> Option<OsString> {
+        let stem = stem.to_str()?;
+        let normalized: String = match self {
+            Normalization::Nfc if !is_nfc(stem) => stem.nfc().collect(),
+            Normalization::Nfd if !is_nfd(stem) => stem.nfd().collect(),
+            _ => return None,
+        };
+        Some(normalized.into())
+    }
+
+    /// Returns `stem` in this form, or unchanged if `normalization` is `None`.
+    pub fn key(normalization: Option<Normalization>, stem: &OsStr) -> OsString {
+        normalization
+            .and_then(|form| form.apply(stem))
+            .unwrap_or_else(|| stem.to_os_string())
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Normalization::Nfc => "NFC",
+            Normalization::Nfd => "NFD",
+        }
+    }
+}
+
+/// A rule as written in the config file or on the command line.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleSpec {
+    /// The preset the pattern comes from, or `None` for user patterns.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    pub pattern: String,
+}
+
+/// A compiled rule.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "RuleSpec", into = "RuleSpec")]
+pub struct Rule {
+    spec: RuleSpec,
+    /// The pattern, anchored to the end of the stem.
+    suffix: Regex,
+}
+
+impl From<Rule> for RuleSpec {
+    fn from(rule: Rule) -> RuleSpec {
+        rule.spec
+    }
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = String;
+
+    fn try_from(spec: RuleSpec) -> Result<Rule, String> {
+        let suffix = Regex::new(&format!("(?:{})$", spec.pattern))
+            .map_err(|e| format!("invalid stem pattern {:?}: {}", spec.pattern, e))?;
+        Ok(Rule { spec, suffix })
+    }
+}
+
+impl Rule {
+    /// Describes the rule for output, e.g. ``lightroom `-Edit(-\d+)?` ``.
+    pub fn describe(&self) -> String {
+        match &self.spec.preset {
+            Some(preset) => format!("{} `{}`", preset, self.spec.pattern),
+            None => format!("pattern `{}`", self.spec.pattern),
+        }
+    }
+}
+
+/// The ordered list of stem rules.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StemRules {
+    rules: Vec<Rule>,
+}
+
+/// A stem to look up, with the rules that produced it.
+pub struct Variant<'a> {
+    pub stem: OsString,
+    /// The form the stem was changed to, if normalizing changed it.
+    pub normalized: Option<Normalization>,
+    pub rules: Vec<&'a Rule>,
+}
+
+impl Variant<'_> {
+    /// Describes how the variant was produced, or `None` for the original stem.
+    pub fn describe(&self) -> Option<String> {
+        let mut steps = Vec::new();
+        if let Some(form) = self.normalized {
+            steps.push(format!("{} stem", form.name()));
+        }
+        if !self.rules.is_empty() {
+            let rules: Vec<String> = self.rules.iter().map(|rule| rule.describe()).collect();
+            steps.push(format!("stem rule {}", rules.join(", then ")));
+        }
+        (!steps.is_empty()).then(|| steps.join(", then "))
+    }
+}
+
+impl StemRules {
+    /// Builds the rules from preset names and user patterns, presets first.
+    pub fn new(presets: &[String], patterns: &[String]) -> Result<StemRules, String> {
+        let mut specs = Vec::new();
+        for name in presets {
+            let Some((preset, patterns)) = PRESETS.iter().find(|(preset, _)| preset == name) else {
+                let names: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+                return Err(format!(
+                    "unknown stem preset `{}`, expected one of {}",
+                    name,
+                    names.join(", ")
+                ));
+            };
+            specs.extend(patterns.iter().map(|pattern| RuleSpec {
+                preset: Some(preset.to_string()),
+                pattern: pattern.to_string(),
+            }));
+        }
+        specs.extend(patterns.iter().map(|pattern| RuleSpec {
+            preset: None,
+            pattern: pattern.clone(),
+        }));
+        let rules = specs
+            .into_iter()
+            .map(Rule::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(StemRules { rules })
+    }
+
+    /// Returns the stems to look up for `stem`, starting with `stem` itself
+    /// in the `normalization` form.
+    ///
+    /// Each further variant strips the suffix of the first rule that matches
+    /// the previous one. Stems that are not UTF-8 are only looked up as is.
+    pub fn variants(&self, stem: &OsStr, normalization: Option<Normalization>) -> Vec<Variant<'_>> {
+        let (stem, normalized) =
+            match normalization.and_then(|form| Some((form.apply(stem)?, form))) {
+                Some((normalized, form)) => (normalized, Some(form)),
+                None => (stem.to_os_string(), None),
+            };
+        let mut variants = vec![Variant {
+            stem: stem.clone(),
+            normalized,
+            rules: Vec::new(),
+        }];
+        let Some(mut current) = stem.to_str().map(str::to_string) else {
+            return variants;
+        };
+        let mut rules = Vec::new();
+
+        for _ in 0..MAX_STRIPS {
+            let Some((rule, start)) = self.rules.iter().find_map(|rule| {
+                let found = rule.suffix.find(&current)?;
+                // Never strip nothing or the whole stem.
+                (found.start() > 0 && !found.is_empty()).then_some((rule, found.start()))
+            }) else {
+                break;
+            };
+            current.truncate(start);
+            rules.push(rule);
+            variants.push(Variant {
+                stem: OsString::from(&current),
+                normalized,
+                rules: rules.clone(),
+            });
+        }
+        variants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stems(rules: &StemRules, stem: &str) -> Vec<String> {
+        rules
+            .variants(OsStr::new(stem), None)
+            .into_iter()
+            .map(|variant| variant.stem.into_string().unwrap())
+            .collect()
+    }
+
+    fn presets(names: &[&str]) -> StemRules {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        StemRules::new(&names, &[]).unwrap()
+    }
+
+    #[test]
+    fn presets_strip_editor_suffixes() {
+        let rules = presets(&["lightroom", "copies"]);
+        assert_eq!(
+            stems(&rules, "DSCF1234-Edit"),
+            ["DSCF1234-Edit", "DSCF1234"]
+        );
+        assert_eq!(
+            stems(&rules, "DSCF1234-Enhanced-NR"),
+            ["DSCF1234-Enhanced-NR", "DSCF1234"]
+        );
+        assert_eq!(
+            stems(&rules, "DSCF1234-Edit (2)"),
+            ["DSCF1234-Edit (2)", "DSCF1234-Edit", "DSCF1234"]
+        );
+        assert_eq!(stems(&rules, "DSCF1234"), ["DSCF1234"]);
+    }
+
+    #[test]
+    fn the_whole_stem_is_never_stripped() {
+        let rules = StemRules::new(&[], &[r"\d*".to_string(), r".*".to_string()]).unwrap();
+        assert_eq!(stems(&rules, "1234"), ["1234"]);
+    }
+
+    #[test]
+    fn stripping_is_bounded() {
+        let rules = presets(&["capture-one"]);
+        let variants = stems(&rules, &format!("A{}", "_1".repeat(20)));
+        assert_eq!(variants.len(), MAX_STRIPS + 1);
+    }
+
+    #[test]
+    fn variants_describe_their_rules() {
+        let rules = StemRules::new(&["darktable".to_string()], &[r"-x".to_string()]).unwrap();
+        let variants = rules.variants(OsStr::new("A_01-x"), None);
+        assert_eq!(variants[0].describe(), None);
+        assert_eq!(variants[1].describe().unwrap(), "stem rule pattern `-x`");
+        assert_eq!(
+            variants[2].describe().unwrap(),
+            r"stem rule pattern `-x`, then darktable `_\d{2}`"
+        );
+    }
+
+    #[test]
+    fn variants_are_normalized_first() {
+        let rules = presets(&["copies"]);
+        let variants = rules.variants(OsStr::new("cafe\u{301} (2)"), Some(Normalization::Nfc));
+        let stems: Vec<&OsStr> = variants
+            .iter()
+            .map(|variant| variant.stem.as_os_str())
+            .collect();
+        assert_eq!(stems, ["caf\u{e9} (2)", "caf\u{e9}"]);
+        assert_eq!(variants[0].describe().unwrap(), "NFC stem");
+        // An already composed stem is not reported as normalized.
+        let variants = rules.variants(OsStr::new("caf\u{e9}"), Some(Normalization::Nfc));
+        assert_eq!(variants[0].normalized, None);
+    }
+
+    #[test]
+    fn unknown_presets_and_bad_patterns_fail() {
+        assert!(StemRules::new(&["photoshop".to_string()], &[]).is_err());
+        assert!(StemRules::new(&[], &["(".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Moving files into the freedesktop.org Trash.
+//!
+//! Implements the parts of the [Trash specification] that are needed to trash
+//! single files: choosing the trash directory of the file's volume, writing the
+//! `.trashinfo` file and moving the file into `files/`, so desktop file managers
+//! can restore it later.
+//!
+//! [Trash specification]: https://specifications.freedesktop.org/trash-spec/latest/
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use walkdir::{DirEntry, WalkDir};
+
+/// Where a file would be trashed to.
+struct Target {
+    /// The absolute path of the file itself.
+    path: PathBuf,
+    /// The trash directory containing `files/` and `info/`.
+    trash_dir: PathBuf,
+    /// The directory `Path=` entries are relative to, or `None` for absolute entries.
+    base: Option<PathBuf>,
+}
+
+/// Returns the trash directory `path` would be moved into, without creating anything.
+pub fn trash_dir_for(path: &Path) -> io::Result<PathBuf> {
+    resolve(path).map(|target| target.trash_dir)
+}
+
+/// Moves `path` into the trash of its volume and returns the trash directory used.
+pub fn trash_file(path: &Path) -> io::Result<PathBuf> {
+    let target = resolve(path)?;
+
+    let files_dir = target.trash_dir.join("files");
+    let info_dir = target.trash_dir.join("info");
+    let mut builder = DirBuilder::new();
+    builder.recursive(true).mode(0o700);
+    builder.create(&files_dir)?;
+    builder.create(&info_dir)?;
+
+    let info_path_value = match &target.base {
+        Some(base) => target.path.strip_prefix(base).unwrap_or(&target.path),
+        None => &target.path,
+    };
+    let info = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        url_encode(info_path_value),
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+    );
+
+    let file_name = target
+        .path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+    // The info file doubles as the lock for the name in `files/`, so it has to be
+    // created exclusively before the file is moved.
+    for attempt in 1u32.. {
+        let name = candidate_name(Path::new(file_name), attempt);
+        let mut info_name = name.clone();
+        info_name.push(".trashinfo");
+        let info_file = info_dir.join(info_name);
+        let trashed_file = files_dir.join(&name);
+
+        if trashed_file.symlink_metadata().is_ok() {
+            continue;
+        }
+        let mut handle = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_file)
+        {
+            Ok(handle) => handle,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+
+        let moved = handle
+            .write_all(info.as_bytes())
+            .and_then(|_| fs::rename(&target.path, &trashed_file));
+        if let Err(e) = moved {
+            let _ = fs::remove_file(&info_file);
+            return Err(e);
+        }
+        return Ok(target.trash_dir);
+    }
+
+    unreachable!("ran out of trash names")
+}
+
+/// Walks `walk` without descending into trash directories, so files trashed
+/// into a root by an earlier run are not picked up again.
+pub fn skip_trash(walk: WalkDir) -> impl Iterator<Item = walkdir::Result<DirEntry>> {
+    let home_trash = home_trash_dir().and_then(fs::canonicalize).ok();
+    walk.into_iter().filter_entry(move |entry| {
+        !(entry.file_type().is_dir() && is_trash_dir(entry.path(), home_trash.as_deref()))
+    })
+}
+
+/// Returns whether the directory `path` is `.Trash`, `.Trash-$uid` or the home trash.
+fn is_trash_dir(path: &Path, home_trash: Option<&Path>) -> bool {
+    let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+        return false;
+    };
+    name == ".Trash"
+        || name.starts_with(".Trash-")
+        || (name == "Trash"
+            && home_trash.is_some_and(|home_trash| {
+                fs::canonicalize(path).is_ok_and(|path| path == home_trash)
+            }))
+}
+
+fn resolve(path: &Path) -> io::Result<Target> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let path = fs::canonicalize(parent)?.join(file_name);
+    let device = fs::symlink_metadata(&path)?.dev();
+
+    let home_trash = home_trash_dir()?;
+    if nearest_existing_device(&home_trash)? == device {
+        return Ok(Target {
+            path,
+            trash_dir: home_trash,
+            base: None,
+        });
+    }
+
+    let topdir = topdir(&path, device)?;
+    let uid = unsafe { libc::getuid() };
+
+    // Method 1: an administrator-created `.Trash` with the sticky bit set.
+    let shared = topdir.join(".Trash");
+    if let Ok(meta) = fs::symlink_metadata(&shared)
+        && meta.is_dir()
+        && meta.permissions().mode() & 0o1000 != 0
+    {
+        return Ok(Target {
+            path,
+            trash_dir: shared.join(uid.to_string()),
+            base: Some(topdir),
+        });
+    }
+
+    // Method 2: a per-user `.Trash-$uid` directory.
+    let own = topdir.join(format!(".Trash-{uid}"));
+    if let Ok(meta) = fs::symlink_metadata(&own)
+        && (!meta.is_dir() || meta.uid() != uid)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a trash directory owned by us", own.display()),
+        ));
+    }
+    Ok(Target {
+        path,
+        trash_dir: own,
+        base: Some(topdir),
+    })
+}
+
+fn home_trash_dir() -> io::Result<PathBuf> {
+    let data_home = match env::var_os("XDG_DATA_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local/share"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "neither XDG_DATA_HOME nor HOME is set",
+                ));
+            }
+        },
+    };
+    Ok(data_home.join("Trash"))
+}
+
+/// Returns the device of `path`, or of its closest ancestor that exists.
+fn nearest_existing_device(path: &Path) -> io::Result<u64> {
+    for ancestor in path.ancestors() {
+        if let Ok(meta) = fs::metadata(ancestor) {
+            return Ok(meta.dev());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no ancestor of {} exists", path.display()),
+    ))
+}
+
+/// Returns the mount point containing `path`, i.e. its topmost ancestor on `device`.
+fn topdir(path: &Path, device: u64) -> io::Result<PathBuf> {
+    let mut top = path.parent().unwrap_or(path);
+    while let Some(parent) = top.parent() {
+        if fs::metadata(parent)?.dev() != device {
+            break;
+        }
+        top = parent;
+    }
+    Ok(top.to_path_buf())
+}
+
+/// Returns `name` for the first attempt and `stem.N.ext` for later ones.
+fn candidate_name(name: &Path, attempt: u32) -> OsString {
+    if attempt == 1 {
+        return name.as_os_str().to_os_string();
+    }
+    let mut candidate = name.file_stem().unwrap_or_default().to_os_string();
+    candidate.push(format!(".{attempt}"));
+    if let Some(ext) = name.extension() {
+        candidate.push(".");
+        candidate.push(ext);
+    }
+    candidate
+}
+
+/// Percent-encodes a path the way the `Path=` key expects.
+fn url_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_encode_escapes_reserved_bytes() {
+        assert_eq!(
+            url_encode(Path::new("/photos/2024/DSCF-1_2.~x.JPG")),
+            "/photos/2024/DSCF-1_2.~x.JPG"
+        );
+        assert_eq!(
+            url_encode(Path::new("/a b/c%d#e?.jpg")),
+            "/a%20b/c%25d%23e%3F.jpg"
+        );
+        assert_eq!(url_encode(Path::new("caf\u{e9}.jpg")), "caf%C3%A9.jpg");
+    }
+
+    #[test]
+    fn candidate_name_numbers_later_attempts() {
+        let name = |name: &str, attempt| candidate_name(Path::new(name), attempt);
+        assert_eq!(name("DSCF1234.JPG", 1), "DSCF1234.JPG");
+        assert_eq!(name("DSCF1234.JPG", 2), "DSCF1234.2.JPG");
+        assert_eq!(name("DSCF1234.JPG.xmp", 3), "DSCF1234.JPG.3.xmp");
+        assert_eq!(name("README", 2), "README.2");
+        assert_eq!(name(".hidden", 2), ".hidden.2");
+    }
+
+    #[test]
+    fn walks_skip_trash_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for file in [
+            "a/A.jpg",
+            "a/A.RAF",
+            ".Trash-1000/files/O.jpg",
+            ".Trash-1000/files/O.RAF",
+            ".Trash/1000/files/P.jpg",
+            "b/.Trash-0/files/Q.jpg",
+        ] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), "").unwrap();
+        }
+        let matcher = crate::matching::Matcher::default();
+
+        let (compressed_files, _) = crate::select::get_compressed_files(root, &matcher);
+        assert_eq!(compressed_files, [root.join("a/A.jpg")]);
+        let (raw_index, _) = matcher.index(root);
+        assert_eq!(raw_index.raws(), [&root.join("a/A.RAF")]);
+    }
+
+    #[test]
+    fn the_home_trash_is_only_recognized_by_its_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let home_trash = tmp.path().join("share/Trash");
+        let other = tmp.path().join("photos/Trash");
+        fs::create_dir_all(&home_trash).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        let home_trash = fs::canonicalize(home_trash).unwrap();
+        assert!(is_trash_dir(&home_trash, Some(&home_trash)));
+        assert!(!is_trash_dir(&other, Some(&home_trash)));
+        assert!(is_trash_dir(Path::new("/x/.Trash-1000"), None));
+        assert!(!is_trash_dir(Path::new("/x/Trash-1000"), None));
+    }
+}